@@ -1,3 +1 @@
-use regex;
-
 pub type BranchRegex = Option<regex::Regex>;
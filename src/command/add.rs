@@ -1,84 +1,205 @@
 //------------------------------------------------------------------------------
-use crate::git;
+use crate::branch_regex::BranchRegex;
+use crate::error::Error;
+use crate::execute;
 use crate::io::{write_to_stderr, write_to_stdout};
+use crate::patch;
 use crate::path;
-use crate::repoiterator::RepoIterator;
-use crate::result::{handle_errors, Result};
+use crate::pathspec;
+use crate::result::{get, Result};
+//------------------------------------------------------------------------------
+use colored::*;
 //------------------------------------------------------------------------------
 use std::env;
+use std::io::Write;
 use std::process;
-use std::thread;
 
 //------------------------------------------------------------------------------
-fn add_changed_thread(path: &path::PathBuf) -> Result<()> {
-    let output = process::Command::new("git")
-        .args(&["add", "-u"])
-        .current_dir(path.clone())
-        .output()?;
+const PATCH_HELP: &str = "y - stage this hunk
+n - do not stage this hunk
+q - quit; do not stage this hunk or any of the remaining ones
+a - stage this hunk and all later hunks in the file
+d - do not stage this hunk or any of the later hunks in the file
+? - print help";
+
+//------------------------------------------------------------------------------
+fn doit(
+    path: &path::Path,
+    options: &execute::Options,
+    args: &[String],
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let mut command = process::Command::new("git");
+    command.arg("add").args(args).current_dir(path);
+
+    let finished = execute::run_in_repo(
+        path,
+        &mut command,
+        options,
+        execute::Capture::Print,
+        out,
+    )?;
+    Ok(finished.outcome)
+}
+
+//------------------------------------------------------------------------------
+fn apply_to_index(repo: &path::Path, patch: &[u8]) -> Result<()> {
+    // Hunks that were left out change the line numbers of the ones after
+    // them, so let git work them out again
+    let mut child = process::Command::new("git")
+        .args(["apply", "--cached", "--recount", "-"])
+        .current_dir(repo)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+
+    get(child.stdin.take())?.write_all(patch)?;
+    let output = child.wait_with_output()?;
 
     // stdout/stderr
-    write_to_stdout(&path, &output.stdout)?;
-    write_to_stderr(&path, &output.stderr)?;
+    write_to_stdout(repo, &output.stdout)?;
+    write_to_stderr(repo, &output.stderr)?;
 
+    if !output.status.success() {
+        return Err(Error::UnableToStage(repo.to_path_buf()));
+    }
     Ok(())
 }
 
 //------------------------------------------------------------------------------
-fn add_changed(regex: &regex::Regex) -> Result<()> {
-    let mut threads = Vec::new();
+// Walk through the unstaged hunks of every repo in one session, staging the
+// chosen ones in the repo they came from
+fn add_patch(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    pathspecs: &[String],
+) -> Result<()> {
+    // A path names its repo, even one the filters leave out, so they're
+    // checked again
+    let repos = pathspec::filtered_repos(regex, branch_regex)?;
+    let per_repo = match pathspecs.is_empty() {
+        true => repos
+            .iter()
+            .map(|repo| (repo.clone(), Vec::new()))
+            .collect(),
+        false => pathspec::resolve(regex, branch_regex, pathspecs)?,
+    };
+    let filtered: Vec<path::PathBuf> =
+        repos.iter().map(|repo| path::normalize(repo)).collect();
 
-    // Loop through the results of what the walker is outputting
-    for path in RepoIterator::new(regex) {
-        // Execute a new thread for processing this result
-        let thread =
-            thread::spawn(move || handle_errors(add_changed_thread(&path)));
+    // Gather everything up front, so the hunks can be counted
+    let mut diffs = Vec::new();
+    for (repo, specs) in per_repo {
+        if !filtered.contains(&path::normalize(&repo)) {
+            continue;
+        }
+        let output = process::Command::new("git")
+            .args(["diff", "--no-color", "--no-ext-diff", "--"])
+            .args(&specs)
+            .current_dir(&repo)
+            .output()?;
+
+        write_to_stderr(&repo, &output.stderr)?;
 
-        threads.push(thread);
+        let files = patch::parse(&output.stdout);
+        if !files.is_empty() {
+            diffs.push((repo, files));
+        }
     }
 
-    // Wait for all the threads to finish
-    for thread in threads {
-        thread.join()?;
+    let total: usize = diffs
+        .iter()
+        .flat_map(|(_, files)| files.iter())
+        .map(|file| file.hunks.len())
+        .sum();
+    if total == 0 {
+        println!("No changes.");
+        return Ok(());
     }
 
-    Ok(())
-}
+    let mut chooser = patch::Chooser::new("Stage this hunk", PATCH_HELP, total);
+    for (repo, files) in &diffs {
+        let mut repo_patch = Vec::new();
 
-//------------------------------------------------------------------------------
-fn add_entry(path: &mut path::PathBuf) -> Result<()> {
-    let (repo, relative_path) = git::relative_to_repo(path)?;
-    let args = ["add", relative_path.as_str()];
-    let output = process::Command::new("git")
-        .args(&args)
-        .current_dir(repo.clone())
-        .output()?;
+        for file in files {
+            let chosen = chooser.choose(file.hunks.len(), |index| {
+                let display = repo.join(&file.path);
+                println!("{0}", display.display().to_string().bold());
+                patch::print_hunk(&file.display_hunk(index))?;
+                Ok(())
+            })?;
 
-    // stdout/stderr
-    write_to_stdout(&repo, &output.stdout)?;
-    write_to_stderr(&repo, &output.stderr)?;
+            if chosen.iter().any(|c| *c) {
+                repo_patch.extend(file.with_hunks(&chosen));
+            }
+        }
+
+        // Stage as we go, so quitting keeps what was chosen so far
+        if !repo_patch.is_empty() {
+            apply_to_index(repo, &repo_patch)?;
+        }
+
+        if chooser.quit {
+            break;
+        }
+    }
 
     Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn run(regex: &regex::Regex, args_pos: usize) -> Result<()> {
+pub fn run(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    options: &execute::Options,
+    args_pos: usize,
+) -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
-    let mut minus_u = false;
+    let mut flags = Vec::new();
+    let mut pathspecs = Vec::new();
+    let mut patch = false;
+    let mut only_pathspecs = false;
     for item in args.iter().skip(args_pos + 1) {
+        if only_pathspecs {
+            pathspecs.push(item.clone());
+            continue;
+        }
         match item.as_str() {
-            "-u" => {
-                if !minus_u {
-                    minus_u = true;
-                    add_changed(regex)?;
-                }
-            }
-            file_path => {
-                let mut path = path::PathBuf::from(file_path);
-                add_entry(&mut path)?;
-            }
+            "-u" | "--update" => flags.push("-u".to_string()),
+            "-A" | "--all" => flags.push("-A".to_string()),
+            "-p" | "--patch" => patch = true,
+            "--" => only_pathspecs = true,
+            _ => pathspecs.push(item.clone()),
         }
     }
 
-    Ok(())
+    if patch {
+        return add_patch(regex, branch_regex, &pathspecs);
+    }
+
+    let task_options = options.clone();
+
+    // Without a pathspec, -u and -A cover the whole of every repo
+    if pathspecs.is_empty() {
+        return execute::for_each_repo(
+            regex,
+            branch_regex,
+            options,
+            move |path, out| doit(path, &task_options, &flags, out),
+        );
+    }
+
+    // Otherwise each repo is given the pathspecs that fall inside it
+    let per_repo = pathspec::resolve(regex, branch_regex, &pathspecs)?;
+    let repos: Vec<path::PathBuf> = per_repo.keys().cloned().collect();
+    execute::for_each(repos, &None, options, move |path, out| {
+        let mut repo_args = flags.clone();
+        repo_args.push("--".to_string());
+        if let Some(specs) = per_repo.get(path) {
+            repo_args.extend(specs.iter().cloned());
+        }
+        doit(path, &task_options, &repo_args, out)
+    })
 }
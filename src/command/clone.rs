@@ -1,72 +1,103 @@
 //------------------------------------------------------------------------------
-use crate::io::{write_to_stderr, write_to_stdout};
+use crate::execute;
+use crate::io::write_to_buffer;
 use crate::path;
-use crate::result::{handle_errors, Result};
+use crate::result::{get, Result};
 //------------------------------------------------------------------------------
+use std::collections::HashMap;
 use std::fs;
 use std::io::BufRead;
 use std::process;
-use std::thread;
 
 //------------------------------------------------------------------------------
-fn doit(dirs: &regex::Regex, url: &str) -> Result<()> {
-    let result: Vec<_> = dirs.captures_iter(url).collect();
-
-    println!("Matching {0}", url);
-
-    const FOLDER: usize = 2;
+fn remove_if_exists(path: &path::Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
 
-    if !result.is_empty() {
-        for i in result {
-            // Clone all the matches
-            let mut path = path::PathBuf::from(".");
-            path.push(&i[FOLDER]);
+//------------------------------------------------------------------------------
+fn doit(
+    options: &execute::Options,
+    url: &str,
+    path: &path::Path,
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    // As with git, only an empty folder can be cloned into
+    let is_empty = match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => true,
+    };
+    if !is_empty {
+        let message = format!(
+            "destination path '{0}' already exists and is not an empty \
+             directory\n",
+            path.display()
+        );
+        write_to_buffer(&mut out.stderr, path, message.as_bytes())?;
+        return Ok(execute::Outcome::Failed(None));
+    }
 
-            //path = fs::canonicalize(path)?;
+    // Clone next to the folder and move it into place once it's complete,
+    // so a clone that was killed part way through can be thrown away and
+    // tried again
+    let parent = get(path.parent())?;
+    let name = get(path.file_name())?.to_string_lossy();
+    let temp_name = format!(".{0}.git-p-clone", name);
+    let temp_path = parent.join(&temp_name);
+    fs::create_dir_all(parent)?;
 
-            // Make the folder
-            fs::create_dir_all(path.as_path())?;
+    let mut command = process::Command::new("git");
+    command
+        .args(["clone", url, temp_name.as_str()])
+        .current_dir(parent);
+    let output =
+        execute::run_prepared_command(path, &mut command, options, || {
+            remove_if_exists(&temp_path)
+        })?;
 
-            // Clone the repo
-            let output = process::Command::new("git")
-                .args(&["clone", url, "."])
-                .current_dir(path.as_path())
-                .output()?;
+    // stdout/stderr
+    write_to_buffer(&mut out.stdout, path, &output.stdout)?;
+    write_to_buffer(&mut out.stderr, path, &output.stderr)?;
 
-            // stdout/stderr
-            write_to_stdout(&path, &output.stdout)?;
-            write_to_stderr(&path, &output.stderr)?;
-        }
+    if output.outcome == execute::Outcome::Success {
+        remove_if_exists(path)?;
+        fs::rename(&temp_path, path)?;
+    } else {
+        remove_if_exists(&temp_path)?;
     }
 
-    Ok(())
+    Ok(output.outcome)
 }
 
 //------------------------------------------------------------------------------
-pub fn run(regex: &regex::Regex) -> Result<()> {
-    let mut threads = Vec::new();
-
+pub fn run(regex: &regex::Regex, options: &execute::Options) -> Result<()> {
     // This will break the git repo url https/http or git into three parts
     // The protocol, the path and the option .git extension
     const GIT_REPO_URL: &str = r"^([a-zA-Z0-9-]+@[a-zA-Z0-9.-]+:|https?://[a-zA-Z0-9.-]+/)([a-zA-Z/-]+)(\.git)?";
+    const FOLDER: usize = 2;
 
-    let dirs_regex = regex::Regex::new(GIT_REPO_URL);
+    let dirs = regex::Regex::new(GIT_REPO_URL)?;
 
-    // Loop over the lines in stdin
+    // Loop over the lines in stdin, working out where each repo goes
+    let mut urls = HashMap::new();
     let stdin = std::io::stdin();
     for l in stdin.lock().lines() {
         let line = l?;
         if regex.is_match(line.as_str()) {
-            let dirs = dirs_regex.clone()?;
-            threads.push(thread::spawn(move || {
-                handle_errors(doit(&dirs, line.as_str()))
-            }));
+            println!("Matching {0}", line);
+            if let Some(captures) = dirs.captures(line.as_str()) {
+                let mut path = path::PathBuf::from(".");
+                path.push(&captures[FOLDER]);
+                urls.insert(path, line);
+            }
         }
     }
 
-    for thread in threads {
-        thread.join()?;
-    }
-
-    Ok(())
+    let paths: Vec<_> = urls.keys().cloned().collect();
+    let task_options = options.clone();
+    execute::for_each(paths, &None, options, move |path, out| {
+        doit(&task_options, &urls[path], path, out)
+    })
 }
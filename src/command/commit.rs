@@ -1,92 +1,474 @@
 //------------------------------------------------------------------------------
 use crate::branch_regex::BranchRegex;
-use crate::filter;
-use crate::io::{write_to_stderr, write_to_stdout};
+use crate::error::Error;
+use crate::execute;
+use crate::git;
+use crate::io::{argument_error, write_to_buffer, write_to_stderr};
 use crate::path;
-use crate::repoiterator::RepoIterator;
+use crate::pathspec;
 use crate::result::{handle_errors, Result};
 //------------------------------------------------------------------------------
-use std::io::{BufRead, BufReader};
+use colored::*;
+//------------------------------------------------------------------------------
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
 use std::process;
-use std::str::FromStr;
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 //------------------------------------------------------------------------------
-fn doit(
-    message: &str,
-    c: &regex::Regex,
-    branch_filter: &BranchRegex,
-    path: &path::PathBuf,
-) -> Result<()> {
-    // Filter based on branch name
-    if let Some(pattern) = branch_filter {
-        if !filter::branch(&pattern, &path)? {
-            return Ok(());
+const EDIT_TEMPLATE: &str = "
+# Please enter the commit message for your changes. It will be used for
+# every repo that has something to commit. Lines starting with '#' will be
+# ignored, and an empty message aborts the commit.
+";
+
+//------------------------------------------------------------------------------
+// Every commit made by one 'git p commit' carries this trailer with the same
+// value, so they can be found again with 'git p show-change'.
+pub const CHANGE_ID_TRAILER: &str = "Poly-Change-Id";
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone, Default)]
+pub struct Options {
+    messages: Vec<String>,
+    file: Option<path::PathBuf>,
+    amend: bool,
+    all: bool,
+    signoff: bool,
+    no_verify: bool,
+    author: Option<String>,
+    change_id: Option<String>,
+    atomic: bool,
+}
+
+//------------------------------------------------------------------------------
+impl Options {
+    fn has_message(&self) -> bool {
+        !self.messages.is_empty() || self.file.is_some()
+    }
+
+    fn git_args(&self) -> Vec<String> {
+        let mut args = vec!["commit".to_string()];
+        for message in &self.messages {
+            args.push("-m".to_string());
+            args.push(message.clone());
+        }
+        if let Some(file) = &self.file {
+            args.push("-F".to_string());
+            args.push(file.to_string_lossy().to_string());
+        }
+        if self.amend {
+            args.push("--amend".to_string());
+            // Keep each repo's own message, there's no editor to open
+            if !self.has_message() {
+                args.push("--no-edit".to_string());
+            }
+        }
+        if self.all {
+            args.push("--all".to_string());
+        }
+        if self.signoff {
+            args.push("--signoff".to_string());
+        }
+        if self.no_verify {
+            args.push("--no-verify".to_string());
+        }
+        if let Some(author) = &self.author {
+            args.push(format!("--author={0}", author));
+        }
+        if let Some(change_id) = &self.change_id {
+            args.push("--trailer".to_string());
+            args.push(format!("{0}: {1}", CHANGE_ID_TRAILER, change_id));
+        }
+        args
+    }
+}
+
+//------------------------------------------------------------------------------
+fn next_value<'a>(
+    args: &'a [String],
+    index: &mut usize,
+    option: &str,
+) -> &'a str {
+    *index += 1;
+    if *index == args.len() {
+        argument_error(&format!("commit {0} requires a value", option));
+    }
+    args[*index].as_str()
+}
+
+//------------------------------------------------------------------------------
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "-m" | "--message" => {
+                let message = next_value(args, &mut index, arg);
+                options.messages.push(message.to_string());
+            }
+            "-F" | "--file" => {
+                let file = next_value(args, &mut index, arg);
+                options.file = Some(path::PathBuf::from(file));
+            }
+            "--amend" => options.amend = true,
+            "--atomic" => options.atomic = true,
+            "-a" | "--all" => options.all = true,
+            "-s" | "--signoff" => options.signoff = true,
+            "-n" | "--no-verify" => options.no_verify = true,
+            "--author" => {
+                let author = next_value(args, &mut index, arg);
+                options.author = Some(author.to_string());
+            }
+            _ => {
+                if let Some(author) = arg.strip_prefix("--author=") {
+                    options.author = Some(author.to_string());
+                } else if let Some(message) = arg.strip_prefix("--message=") {
+                    options.messages.push(message.to_string());
+                } else if let Some(file) = arg.strip_prefix("--file=") {
+                    options.file = Some(path::PathBuf::from(file));
+                } else {
+                    argument_error(&format!(
+                        "commit doesn't support '{0}'",
+                        arg
+                    ));
+                }
+            }
+        }
+        index += 1;
+    }
+
+    if !options.messages.is_empty() && options.file.is_some() {
+        argument_error("commit can't combine -m and -F");
+    }
+
+    options
+}
+
+//------------------------------------------------------------------------------
+fn new_change_id() -> String {
+    // RandomState is seeded randomly for every instance
+    let mut id = String::new();
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(process::id());
+        if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
         }
+        id.push_str(&format!("{0:016x}", hasher.finish()));
     }
+    id
+}
 
-    let args = ["status", "--porcelain"];
+//------------------------------------------------------------------------------
+fn message_file_path() -> path::PathBuf {
+    env::temp_dir().join(format!("git-poly-COMMIT_EDITMSG-{0}", process::id()))
+}
+
+//------------------------------------------------------------------------------
+// Open the editor once, for a message that's shared by all the repos
+fn edit_message(file_path: &path::Path) -> Result<()> {
+    fs::write(file_path, EDIT_TEMPLATE)?;
+
+    // Ask git which editor to use, so core.editor, GIT_EDITOR, VISUAL and
+    // EDITOR are all respected
     let output = process::Command::new("git")
-        .args(&args)
-        .current_dir(path.clone())
+        .args(["var", "GIT_EDITOR"])
         .output()?;
+    let editor = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if editor.is_empty() {
+        return Err(Error::NoEditor);
+    }
 
-    write_to_stderr(&path, &output.stderr)?;
-
-    // Search for modifications
-    let stdout = BufReader::new(&output.stdout as &[u8]);
-    let mut lines = stdout.lines();
-    let has_modifications = {
-        loop {
-            if let Some(result) = lines.next() {
-                let line = result?;
-                if c.is_match(line.as_str()) {
-                    break true;
-                }
-            } else {
-                break false;
+    // The editor can have arguments, so let the shell split it up
+    let status = process::Command::new("sh")
+        .args(["-c", &format!("{0} \"$@\"", editor), &editor])
+        .arg(file_path)
+        .status()?;
+    if !status.success() {
+        return Err(Error::NoEditor);
+    }
+
+    // Strip the comments, as git would
+    let edited = fs::read_to_string(file_path)?;
+    let message: Vec<_> = edited
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect();
+    let message = message.join("\n");
+    if message.trim().is_empty() {
+        return Err(Error::EmptyCommitMessage);
+    }
+
+    fs::write(file_path, message.trim().to_string() + "\n")?;
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Whether the commit would record anything, ie the index differs from HEAD.
+// With --all the changes to tracked files in the working tree count too.
+fn has_changes(
+    options: &Options,
+    path: &path::Path,
+    out: &mut execute::Output,
+) -> Result<bool> {
+    let mut args = vec!["diff", "--quiet"];
+    if options.all && git::current_head(path)?.is_some() {
+        args.push("HEAD");
+    } else {
+        args.push("--cached");
+    }
+
+    let output = process::Command::new("git")
+        .args(args)
+        .current_dir(path)
+        .output()?;
+
+    write_to_buffer(&mut out.stderr, path, &output.stderr)?;
+
+    // --quiet exits with 1 when there are differences
+    match output.status.code() {
+        Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        _ => Err(Error::UnableToDiff),
+    }
+}
+
+//------------------------------------------------------------------------------
+// The Poly-Change-Id of the last commit, if it has one
+fn head_change_id(path: &path::Path) -> Result<Option<String>> {
+    let format =
+        format!("--format=%(trailers:key={0},valueonly)", CHANGE_ID_TRAILER);
+    let output = process::Command::new("git")
+        .args(["log", "-1", &format])
+        .current_dir(path)
+        .output()?;
+
+    let change_id = String::from_utf8_lossy(&output.stdout);
+    Ok(change_id.lines().next().map(|id| id.trim().to_string()))
+}
+
+//------------------------------------------------------------------------------
+// The repos with nothing staged that an amend with a new message rewords
+// anyway: those whose last commit is part of a change that's being amended
+// in another repo, so the change keeps one message. The last commit of any
+// other repo is left alone, as it may well have been pushed.
+fn repos_to_reword(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    options: &Options,
+) -> Result<HashSet<path::PathBuf>> {
+    let mut amended = HashSet::new();
+    let mut unchanged = Vec::new();
+    for repo in pathspec::filtered_repos(regex, branch_regex)? {
+        let mut out = execute::Output::default();
+        let changes = has_changes(options, &repo, &mut out)?;
+        std::io::stderr().write_all(&out.stderr)?;
+
+        match (changes, head_change_id(&repo)?) {
+            (true, Some(change_id)) => {
+                amended.insert(change_id);
             }
+            (false, Some(change_id)) => unchanged.push((repo, change_id)),
+            _ => (),
         }
-    };
+    }
+
+    Ok(unchanged
+        .into_iter()
+        .filter(|(_, change_id)| amended.contains(change_id))
+        .map(|(repo, _)| repo)
+        .collect())
+}
+
+//------------------------------------------------------------------------------
+fn doit(
+    options: &Options,
+    execute_options: &execute::Options,
+    rewords: &HashSet<path::PathBuf>,
+    report: &Mutex<Report>,
+    path: &path::Path,
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    // Even when amending, a repo without changes is left alone, rather than
+    // rewriting the last commit of every repo, unless it's part of the same
+    // change
+    if !rewords.contains(path) && !has_changes(options, path, out)? {
+        report.lock()?.nothing_staged.push(path.to_path_buf());
+        return Ok(execute::Outcome::Success);
+    }
+
+    // Remember where we were, to be able to roll back
+    let previous_head = git::current_head(path)?;
+
+    let mut command = process::Command::new("git");
+    command.args(options.git_args()).current_dir(path);
+    let output = execute::run_in_repo(
+        path,
+        &mut command,
+        execute_options,
+        execute::Capture::Print,
+        out,
+    )?;
+
+    if output.outcome == execute::Outcome::Success {
+        report
+            .lock()?
+            .committed
+            .push((path.to_path_buf(), previous_head));
+    }
+
+    Ok(output.outcome)
+}
 
-    // If we have modifications then do a commit
-    if has_modifications {
-        let output = process::Command::new("git")
-            .args(&["commit", "-m", message])
-            .current_dir(path.clone())
-            .output()?;
+//------------------------------------------------------------------------------
+// Undo a commit, keeping what it recorded staged
+fn roll_back(path: &path::Path, previous_head: &Option<String>) -> Result<()> {
+    let mut command = process::Command::new("git");
+    match previous_head {
+        Some(head) => command.args(["reset", "--soft", head.as_str()]),
+        None => command.args(["update-ref", "-d", "HEAD"]),
+    };
+    let output = command.current_dir(path).output()?;
 
-        write_to_stderr(&path, &output.stderr)?;
-        write_to_stdout(&path, &output.stdout)?;
+    write_to_stderr(path, &output.stderr)?;
+    if !output.status.success() {
+        return Err(Error::UnableToRollBack(path.to_path_buf()));
     }
 
     Ok(())
 }
 
+//------------------------------------------------------------------------------
+// Report
+//------------------------------------------------------------------------------
+#[derive(Default)]
+struct Report {
+    committed: Vec<(path::PathBuf, Option<String>)>,
+    nothing_staged: Vec<path::PathBuf>,
+    rolled_back: Vec<path::PathBuf>,
+}
+
+//------------------------------------------------------------------------------
+impl Report {
+    fn print(&mut self) {
+        self.committed.sort();
+        self.nothing_staged.sort();
+        self.rolled_back.sort();
+
+        if !self.committed.is_empty() {
+            println!();
+            println!("{0}", "Committed:".green());
+            for (path, _) in &self.committed {
+                println!("        {0}", path.display());
+            }
+        }
+        if !self.rolled_back.is_empty() {
+            println!();
+            println!("{0}", "Committed, then rolled back:".red());
+            for path in &self.rolled_back {
+                println!("        {0}", path.display());
+            }
+        }
+        if !self.nothing_staged.is_empty() {
+            println!();
+            println!("{0}", "Nothing staged:".yellow());
+            for path in &self.nothing_staged {
+                println!("        {0}", path.display());
+            }
+        }
+    }
+}
+
 //------------------------------------------------------------------------------
 pub fn run(
     regex: &regex::Regex,
     branch_regex: &BranchRegex,
-    msg: &str,
+    execute_options: &execute::Options,
+    options: &Options,
 ) -> Result<()> {
-    let mut threads = Vec::new();
+    let mut options = options.clone();
 
-    let changes = regex::Regex::new(r"^(M|A|D) .*")?;
+    // Every repo commits from its own directory, so the message file needs
+    // to be absolute. Reading it from stdin can only be done once.
+    let mut temporary = None;
+    if let Some(file) = &options.file {
+        if file.as_os_str() == "-" {
+            let mut message = Vec::new();
+            std::io::stdin().read_to_end(&mut message)?;
+            let file_path = message_file_path();
+            fs::write(&file_path, message)?;
+            temporary = Some(file_path);
+        } else {
+            options.file = Some(fs::canonicalize(file)?);
+        }
+    } else if options.messages.is_empty() && !options.amend {
+        let file_path = message_file_path();
+        temporary = Some(file_path.clone());
+        if let Err(error) = edit_message(&file_path) {
+            let _ = fs::remove_file(&file_path);
+            return Err(error);
+        }
+    }
+    if let Some(file_path) = &temporary {
+        options.file = Some(file_path.clone());
+    }
 
-    for path in RepoIterator::new(regex) {
-        let message = String::from_str(msg)?;
-        let c = changes.clone();
-        let branch_filter = branch_regex.clone();
+    let rewords = match options.amend && options.has_message() {
+        true => repos_to_reword(regex, branch_regex, &options)?,
+        false => HashSet::new(),
+    };
 
-        threads.push(thread::spawn(move || {
-            handle_errors(doit(&message, &c, &branch_filter, &path))
-        }));
+    // Amending without a new message keeps the id it already has
+    let change_id = new_change_id();
+    if !options.amend || options.has_message() {
+        options.change_id = Some(change_id.clone());
     }
 
-    // Wait for all the threads to finish
-    for thread in threads {
-        thread.join()?;
+    let report = Arc::new(Mutex::new(Report::default()));
+    let task_report = report.clone();
+    let task_options = execute_options.clone();
+    let options_change_id = options.change_id.is_some();
+    let options_atomic = options.atomic;
+    let result = execute::for_each_repo(
+        regex,
+        branch_regex,
+        execute_options,
+        move |path, out| {
+            doit(&options, &task_options, &rewords, &task_report, path, out)
+        },
+    );
+
+    let mut report = report.lock()?;
+
+    // All or nothing, so undo the commits that did succeed
+    if options_atomic && result.is_err() {
+        for (path, previous_head) in std::mem::take(&mut report.committed) {
+            match roll_back(&path, &previous_head) {
+                Ok(()) => report.rolled_back.push(path),
+                Err(error) => handle_errors::<()>(Err(error)),
+            }
+        }
     }
 
-    Ok(())
+    report.print();
+    if !report.committed.is_empty() && options_change_id {
+        println!();
+        println!("{0}: {1}", CHANGE_ID_TRAILER, change_id.cyan());
+    }
+
+    if let Some(file_path) = temporary {
+        fs::remove_file(file_path)?;
+    }
+
+    result
 }
@@ -1,71 +1,763 @@
 //------------------------------------------------------------------------------
 use crate::branch_regex::BranchRegex;
-use crate::filter;
-use crate::io::write_to_stderr;
+use crate::execute;
+use crate::filetype;
+use crate::git;
+use crate::io::{argument_error, write_line};
 use crate::path;
-use crate::repoiterator::RepoIterator;
-use crate::result::{handle_errors, Result};
+use crate::result::Result;
 //------------------------------------------------------------------------------
-use std::io::{BufRead, BufReader};
+use colored::*;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{IsTerminal, Write};
 use std::process;
-use std::thread;
+use std::sync::{Arc, Mutex};
 
 //------------------------------------------------------------------------------
-fn grep_thread(
-    expr: &str,
-    branch_filter: &BranchRegex,
-    path: &path::PathBuf,
-) -> Result<()> {
-    // Filter based on branch name
-    if let Some(pattern) = branch_filter {
-        if !filter::branch(&pattern, &path)? {
-            return Ok(());
+// The git grep options that take the next argument as their value
+const OPTIONS_WITH_VALUE: &[&str] = &[
+    "-e",
+    "-f",
+    "--file",
+    "-A",
+    "-B",
+    "-C",
+    "-m",
+    "--after-context",
+    "--before-context",
+    "--context",
+    "--max-count",
+    "--max-depth",
+    "--threads",
+];
+
+//------------------------------------------------------------------------------
+// What the matches are counted by with --count-by
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CountBy {
+    Repo,
+    File,
+    Branch,
+}
+
+//------------------------------------------------------------------------------
+impl CountBy {
+    fn name(self) -> &'static str {
+        match self {
+            CountBy::Repo => "repo",
+            CountBy::File => "file",
+            CountBy::Branch => "branch",
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone, Default)]
+pub struct Options {
+    // Everything for git grep but the pathspecs, which the selection has
+    pub grep_args: Vec<String>,
+    pub selection: filetype::Selection,
+    // The revisions to search instead of the working tree
+    pub revs: Vec<String>,
+    pub all_branches: bool,
+    // Print a table of the number of matches instead of the matches, or the
+    // same as json
+    pub count_by: Option<CountBy>,
+    pub json: bool,
+}
+
+//------------------------------------------------------------------------------
+fn parse_count_by(arg: &str) -> CountBy {
+    match arg {
+        "repo" => CountBy::Repo,
+        "file" => CountBy::File,
+        "branch" => CountBy::Branch,
+        _ => {
+            argument_error(&format!(
+                "unknown --count-by '{0}', it can be repo, file or branch",
+                arg
+            ));
+            CountBy::Repo
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// git grep runs in each repo, so a file of patterns has to be given as an
+// absolute path for it to be found from there. It's always passed with -f,
+// as git grep has no --file.
+fn pattern_file(file: &str) -> String {
+    match fs::canonicalize(file) {
+        Ok(file) => file.to_string_lossy().into_owned(),
+        Err(_) => {
+            argument_error(&format!("can't read the patterns in '{0}'", file));
+            String::new()
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// As with git grep, the first argument that isn't an option is the pattern,
+// unless one is given with -e or -f, and the rest are pathspecs
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut has_pattern = false;
+    let mut only_pathspecs = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+
+        if only_pathspecs {
+            options.selection.pathspecs.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "--type" => {
+                if i == args.len() {
+                    argument_error("--type requires a type");
+                }
+                options.selection.add_type(&args[i]);
+                i += 1;
+            }
+            "--rev" => {
+                if i == args.len() {
+                    argument_error("--rev requires a revision");
+                }
+                options.revs.push(args[i].clone());
+                i += 1;
+            }
+            "--all-branches" => options.all_branches = true,
+            "--count-by" => {
+                if i == args.len() {
+                    argument_error("--count-by requires repo, file or branch");
+                }
+                options.count_by = Some(parse_count_by(&args[i]));
+                i += 1;
+            }
+            "--json" => options.json = true,
+            "--" => only_pathspecs = true,
+            _ if arg.starts_with("--type=") => {
+                options.selection.add_type(&arg["--type=".len()..]);
+            }
+            _ if arg.starts_with("--count-by=") => {
+                options.count_by =
+                    Some(parse_count_by(&arg["--count-by=".len()..]));
+            }
+            _ if arg.starts_with("--rev=") => {
+                options.revs.push(arg["--rev=".len()..].to_string());
+            }
+            _ if OPTIONS_WITH_VALUE.contains(&arg.as_str()) => {
+                if i == args.len() {
+                    argument_error(&format!("{0} requires a value", arg));
+                }
+                let is_file = arg == "-f" || arg == "--file";
+                if arg == "-e" || is_file {
+                    has_pattern = true;
+                }
+                match is_file {
+                    true => {
+                        options.grep_args.push("-f".to_string());
+                        options.grep_args.push(pattern_file(&args[i]));
+                    }
+                    false => {
+                        options.grep_args.push(arg.clone());
+                        options.grep_args.push(args[i].clone());
+                    }
+                }
+                i += 1;
+            }
+            _ if arg.starts_with("--file=") => {
+                has_pattern = true;
+                options.grep_args.push("-f".to_string());
+                options
+                    .grep_args
+                    .push(pattern_file(&arg["--file=".len()..]));
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                options.grep_args.push(arg.clone());
+            }
+            _ if !has_pattern => {
+                options.grep_args.push("-e".to_string());
+                options.grep_args.push(arg.clone());
+                has_pattern = true;
+            }
+            _ => options.selection.pathspecs.push(arg.clone()),
+        }
+    }
+
+    // The json is only ever of the counts
+    if options.json && options.count_by.is_none() {
+        options.count_by = Some(CountBy::Repo);
+    }
+
+    if !has_pattern {
+        argument_error(
+            "Please provide the expression you would like to grep for",
+        );
+    }
+
+    options
+}
+
+//------------------------------------------------------------------------------
+fn without_colors(line: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::new();
+    let mut in_escape = false;
+    for byte in line {
+        match byte {
+            b'\x1b' => in_escape = true,
+            b'm' if in_escape => in_escape = false,
+            _ if !in_escape => plain.push(*byte),
+            _ => (),
+        }
+    }
+    plain
+}
+
+//------------------------------------------------------------------------------
+// The line between groups of context lines, which may be coloured
+fn is_separator(line: &[u8]) -> bool {
+    let plain = without_colors(line);
+    plain == b"--\n" || plain == b"--"
+}
+
+//------------------------------------------------------------------------------
+// A path as git writes it in its output, in quotes with C style escapes
+// when it has anything unusual in it
+fn quote_path(file: &str) -> Vec<u8> {
+    let bytes = file.as_bytes();
+    let unusual = |byte: &u8| {
+        *byte < 0x20 || *byte >= 0x7f || *byte == b'"' || *byte == b'\\'
+    };
+    if !bytes.iter().any(unusual) {
+        return bytes.to_vec();
+    }
+
+    let mut quoted = vec![b'"'];
+    for byte in bytes {
+        match byte {
+            b'"' => quoted.extend_from_slice(b"\\\""),
+            b'\\' => quoted.extend_from_slice(b"\\\\"),
+            b'\t' => quoted.extend_from_slice(b"\\t"),
+            b'\n' => quoted.extend_from_slice(b"\\n"),
+            b'\r' => quoted.extend_from_slice(b"\\r"),
+            byte if unusual(byte) => {
+                quoted.extend_from_slice(format!("\\{0:03o}", byte).as_bytes())
+            }
+            byte => quoted.push(*byte),
+        }
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+//------------------------------------------------------------------------------
+// The files that were selected, both as git quotes them and as they are, in
+// case core.quotePath is off
+fn selected_files(files: Vec<String>) -> HashSet<Vec<u8>> {
+    let mut selected = HashSet::new();
+    for file in files {
+        selected.insert(quote_path(&file));
+        selected.insert(file.into_bytes());
+    }
+    selected
+}
+
+//------------------------------------------------------------------------------
+// Whether a line of output is from one of the files. The path comes first,
+// after the revision when there is one, and ends at a separator or at the
+// end of the line with -l.
+fn is_from(line: &[u8], revs: &[String], files: &HashSet<Vec<u8>>) -> bool {
+    let plain = without_colors(line);
+    let mut path = plain.strip_suffix(b"\n").unwrap_or(&plain);
+    for rev in revs {
+        if let Some(rest) = path.strip_prefix(rev.as_bytes()) {
+            if let Some(rest) = rest.strip_prefix(b":") {
+                path = rest;
+                break;
+            }
+        }
+    }
+
+    (1..=path.len())
+        .filter(|end| *end == path.len() || b":-=\0".contains(&path[*end]))
+        .any(|end| files.contains(&path[..end]))
+}
+
+//------------------------------------------------------------------------------
+// Just the lines from the files, with the separators between groups of
+// context lines that are left
+fn select_lines(
+    stdout: &[u8],
+    revs: &[String],
+    files: &HashSet<Vec<u8>>,
+) -> Vec<u8> {
+    let mut selected = Vec::new();
+    let mut separator: Option<&[u8]> = None;
+    for line in stdout.split_inclusive(|byte| *byte == b'\n') {
+        if is_separator(line) {
+            separator = Some(line);
+        } else if is_from(line, revs, files) {
+            if let Some(separator) = separator.take() {
+                if !selected.is_empty() {
+                    selected.extend_from_slice(separator);
+                }
+            }
+            selected.extend_from_slice(line);
+        }
+    }
+    selected
+}
+
+//------------------------------------------------------------------------------
+// Count
+//------------------------------------------------------------------------------
+// The number of matches in one file of one repo, at one revision
+pub struct Count {
+    repo: String,
+    branch: String,
+    file: String,
+    count: usize,
+}
+
+//------------------------------------------------------------------------------
+// The counts from 'git grep --only-matching --null', which writes a line for
+// every match starting with 'path\0', or 'rev:path\0' when revisions are
+// searched. Without a revision the branch is the one that's checked out.
+fn parse_counts(
+    grep_options: &Options,
+    path: &path::Path,
+    stdout: &[u8],
+) -> Result<Vec<Count>> {
+    let searches_revs =
+        !grep_options.revs.is_empty() || grep_options.all_branches;
+    let checked_out = match searches_revs {
+        true => String::new(),
+        false => git::get_branch_name(path)?,
+    };
+    let flat_path = path.join(path::Path::new(""));
+
+    // The matches of a file come together
+    let mut matches: Vec<(&[u8], usize)> = Vec::new();
+    for line in stdout.split(|byte| *byte == b'\n') {
+        let file = match line.iter().position(|byte| *byte == 0) {
+            Some(end) => &line[..end],
+            None => continue,
+        };
+        match matches.last_mut() {
+            Some((last, count)) if *last == file => *count += 1,
+            _ => matches.push((file, 1)),
         }
     }
 
-    let output = process::Command::new("git")
-        .args(&["grep", expr])
-        .current_dir(path.clone())
-        .output()?;
+    let mut counts = Vec::new();
+    for (file, count) in matches {
+        let file = String::from_utf8_lossy(file);
+        let branch = match searches_revs {
+            true => file.split_once(':').map_or(&*file, |(rev, _)| rev),
+            false => &checked_out,
+        };
+        counts.push(Count {
+            repo: path.display().to_string(),
+            branch: branch.to_string(),
+            file: format!("{0}{1}", flat_path.display(), file),
+            count,
+        });
+    }
+    Ok(counts)
+}
+
+//------------------------------------------------------------------------------
+// The total for each repo, file or branch, the most matches first
+fn totals(counts: &[Count], count_by: CountBy) -> Vec<(String, usize)> {
+    let mut totals: BTreeMap<&str, usize> = BTreeMap::new();
+    for count in counts {
+        let key = match count_by {
+            CountBy::Repo => &count.repo,
+            CountBy::File => &count.file,
+            CountBy::Branch => &count.branch,
+        };
+        *totals.entry(key).or_default() += count.count;
+    }
 
-    write_to_stderr(&path, &output.stderr)?;
+    let mut totals: Vec<(String, usize)> = totals
+        .into_iter()
+        .map(|(key, total)| (key.to_string(), total))
+        .collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    totals
+}
 
-    let outstream = std::io::stdout();
-    {
-        let _handle = outstream.lock();
-        let stdout = BufReader::new(&output.stdout as &[u8]);
-        let flat_path = path.as_path().join(path::Path::new(""));
-        for line in stdout.lines() {
-            print!("{0}", flat_path.display());
-            println!("{0}", line?);
+//------------------------------------------------------------------------------
+fn print_table(totals: &[(String, usize)]) {
+    let total: usize = totals.iter().map(|(_, count)| count).sum();
+    let width = total.to_string().len();
+    for (key, count) in totals {
+        println!("{0:>1$}  {2}", count, width, key);
+    }
+    println!("{0:>1$}  {2}", total, width, "total".bold());
+}
+
+//------------------------------------------------------------------------------
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{0:04x}", c as u32))
+            }
+            c => escaped.push(c),
         }
     }
+    escaped.push('"');
+    escaped
+}
+
+//------------------------------------------------------------------------------
+fn print_json(totals: &[(String, usize)], count_by: CountBy) {
+    let total: usize = totals.iter().map(|(_, count)| count).sum();
+    let counts: Vec<String> = totals
+        .iter()
+        .map(|(key, count)| {
+            format!(
+                "    {{\"{0}\": {1}, \"count\": {2}}}",
+                count_by.name(),
+                json_string(key),
+                count
+            )
+        })
+        .collect();
 
-    Ok(())
+    println!("{{");
+    println!("  \"count_by\": \"{0}\",", count_by.name());
+    println!("  \"total\": {0},", total);
+    if counts.is_empty() {
+        println!("  \"counts\": []");
+    } else {
+        println!("  \"counts\": [\n{0}\n  ]", counts.join(",\n"));
+    }
+    println!("}}");
+}
+
+//------------------------------------------------------------------------------
+// The revisions to search in the repo, leaving out any it doesn't have, or
+// None to search the working tree
+fn revisions(
+    grep_options: &Options,
+    path: &path::Path,
+) -> Result<Option<Vec<String>>> {
+    if grep_options.revs.is_empty() && !grep_options.all_branches {
+        return Ok(None);
+    }
+
+    let mut revs = Vec::new();
+    if grep_options.all_branches {
+        revs = git::local_branches(path)?;
+    }
+    for rev in &grep_options.revs {
+        if !revs.contains(rev) && git::has_commit(path, rev)? {
+            revs.push(rev.clone());
+        }
+    }
+    Ok(Some(revs))
+}
+
+//------------------------------------------------------------------------------
+fn grep_thread(
+    grep_options: &Options,
+    options: &execute::Options,
+    counts: Option<&Mutex<Vec<Count>>>,
+    path: &path::Path,
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let selection = &grep_options.selection;
+    let selected = match selection.has_pathspecs_and_types() {
+        true => match selection.list_files(path, out)? {
+            Some(files) => Some(selected_files(files)),
+            None => return Ok(execute::Outcome::Failed(None)),
+        },
+        false => None,
+    };
+
+    // Nothing in this repo was selected
+    if selected.as_ref().is_some_and(|files| files.is_empty()) {
+        return Ok(execute::Outcome::Success);
+    }
+
+    // Nor are there any of the revisions to search
+    let revs = revisions(grep_options, path)?;
+    if revs.as_ref().is_some_and(|revs| revs.is_empty()) {
+        return Ok(execute::Outcome::Success);
+    }
+
+    // Git only colours what it writes to a terminal, and here it writes to
+    // a pipe
+    let mut command = process::Command::new("git");
+    command.arg("grep");
+    if counts.is_some() {
+        command.arg("--color=never");
+    } else if std::io::stdout().is_terminal() {
+        command.arg("--color=always");
+    }
+    command.args(&grep_options.grep_args);
+    if counts.is_some() {
+        command.args(["--only-matching", "--null"]);
+    }
+    let revs = revs.unwrap_or_default();
+    command
+        .args(&revs)
+        .arg("--")
+        .args(selection.git_pathspecs())
+        .current_dir(path);
+    // The output is rewritten, so it's kept rather than streamed
+    let mut output = execute::run_in_repo(
+        path,
+        &mut command,
+        options,
+        execute::Capture::Keep,
+        out,
+    )?;
+    if let Some(files) = &selected {
+        output.stdout = select_lines(&output.stdout, &revs, files);
+    }
+
+    // Each line starts with the path, after the revision when there is one,
+    // apart from the '--' between groups of context lines
+    if let Some(counts) = counts {
+        let found = parse_counts(grep_options, path, &output.stdout)?;
+        counts.lock()?.extend(found);
+    } else {
+        let flat_path = path.join(path::Path::new(""));
+        let prefix = flat_path.display().to_string().magenta();
+        for line in output.stdout.split_inclusive(|byte| *byte == b'\n') {
+            if !is_separator(line) {
+                write!(out.stdout, "{0}", prefix)?;
+            }
+            write_line(&mut out.stdout, line);
+        }
+    }
+
+    // git grep exits with 1 when there's nothing found
+    match output.outcome {
+        execute::Outcome::Failed(Some(1)) => Ok(execute::Outcome::Success),
+        outcome => Ok(outcome),
+    }
 }
 
 //------------------------------------------------------------------------------
 pub fn run(
     regex: &regex::Regex,
     branch_regex: &BranchRegex,
-    expression: &str,
+    options: &execute::Options,
+    grep_options: &Options,
 ) -> Result<()> {
-    let mut threads = Vec::new();
+    let count_by = grep_options.count_by;
+    let json = grep_options.json;
+    let grep_options = grep_options.clone();
+
+    let task_options = options.clone();
+
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let task_counts = counts.clone();
+    let result = execute::for_each_repo(
+        regex,
+        branch_regex,
+        options,
+        move |path, out| {
+            let counts = grep_options.count_by.map(|_| task_counts.as_ref());
+            grep_thread(&grep_options, &task_options, counts, path, out)
+        },
+    );
+
+    // The table is of every repo that could be searched, even when some
+    // couldn't
+    if let Some(count_by) = count_by {
+        let totals = totals(&counts.lock()?, count_by);
+        if json {
+            print_json(&totals, count_by);
+        } else {
+            print_table(&totals);
+        }
+    }
+
+    result
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    fn files(files: &[&str]) -> HashSet<Vec<u8>> {
+        selected_files(files.iter().map(|file| file.to_string()).collect())
+    }
+
+    //--------------------------------------------------------------------------
+    fn parse(args: &[&str]) -> Options {
+        parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn the_first_argument_is_the_pattern() {
+        let options = parse(&["-i", "main", "src", "--", "-odd"]);
+        assert_eq!(options.grep_args, ["-i", "-e", "main"]);
+        assert_eq!(options.selection.pathspecs, ["src", "-odd"]);
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn options_take_their_values() {
+        let options = parse(&["-A", "2", "-e", "main", "--rev", "v1", "src"]);
+        assert_eq!(options.grep_args, ["-A", "2", "-e", "main"]);
+        assert_eq!(options.revs, ["v1"]);
+        assert_eq!(options.selection.pathspecs, ["src"]);
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn pattern_files_are_made_absolute() {
+        let absolute = fs::canonicalize("Cargo.toml").unwrap();
+        let absolute = absolute.to_string_lossy();
+        for args in [
+            &["-f", "Cargo.toml", "src"][..],
+            &["--file", "Cargo.toml", "src"],
+            &["--file=Cargo.toml", "src"],
+        ] {
+            let options = parse(args);
+            assert_eq!(options.grep_args, ["-f", &*absolute]);
+            assert_eq!(options.selection.pathspecs, ["src"]);
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn unusual_paths_are_quoted_as_git_does() {
+        assert_eq!(quote_path("src/a.rs"), b"src/a.rs");
+        assert_eq!(quote_path("a b.txt"), b"a b.txt");
+        assert_eq!(quote_path("a\tb.txt"), b"\"a\\tb.txt\"");
+        assert_eq!(quote_path("say \"hi\".txt"), b"\"say \\\"hi\\\".txt\"");
+        assert_eq!(quote_path("caf\u{e9}.txt"), b"\"caf\\303\\251.txt\"");
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn lines_are_matched_by_their_path() {
+        let selected = files(&["src/a.rs", "a\tb.txt"]);
+        assert!(is_from(b"src/a.rs:fn main() {\n", &[], &selected));
+        assert!(is_from(b"src/a.rs-12-context\n", &[], &selected));
+        assert!(is_from(b"src/a.rs\n", &[], &selected));
+        assert!(is_from(b"\"a\\tb.txt\":text\n", &[], &selected));
+        assert!(!is_from(b"src/a.rsx:fn main() {\n", &[], &selected));
+        assert!(!is_from(b"src/b.rs:src/a.rs:\n", &[], &selected));
+    }
 
-    // Loop through the results of what the walker is outputting
-    for path in RepoIterator::new(regex) {
-        let expr = expression.to_string();
-        let branch_filter = branch_regex.clone();
+    //--------------------------------------------------------------------------
+    #[test]
+    fn the_revision_comes_before_the_path() {
+        let selected = files(&["src/a.rs"]);
+        let revs = ["main".to_string()];
+        assert!(is_from(b"main:src/a.rs:fn main() {\n", &revs, &selected));
+        assert!(!is_from(b"other:src/a.rs:fn main() {\n", &revs, &selected));
+    }
 
-        threads.push(thread::spawn(move || {
-            handle_errors(grep_thread(&expr, &branch_filter, &path))
-        }));
+    //--------------------------------------------------------------------------
+    #[test]
+    fn colors_are_ignored() {
+        let selected = files(&["src/a.rs"]);
+        let line = b"\x1b[35msrc/a.rs\x1b[m\x1b[36m:\x1b[mfn main() {\n";
+        assert!(is_from(line, &[], &selected));
     }
 
-    // Wait for all the threads to finish
-    for thread in threads {
-        thread.join()?;
+    //--------------------------------------------------------------------------
+    #[test]
+    fn separators_are_only_kept_between_selected_lines() {
+        let selected = files(&["a.rs"]);
+        let stdout = b"b.rs-1-x\nb.rs:2:y\n--\na.rs-1-x\na.rs:2:y\n--\n\
+                       b.rs:7:y\n--\na.rs:9:y\n";
+        assert_eq!(
+            select_lines(stdout, &[], &selected),
+            b"a.rs-1-x\na.rs:2:y\n--\na.rs:9:y\n"
+        );
     }
 
-    Ok(())
+    //--------------------------------------------------------------------------
+    fn counts(revs: &[&str], stdout: &[u8]) -> Vec<(String, String, usize)> {
+        let options = Options {
+            revs: revs.iter().map(|rev| rev.to_string()).collect(),
+            ..Options::default()
+        };
+        parse_counts(&options, path::Path::new("repo"), stdout)
+            .unwrap()
+            .into_iter()
+            .map(|count| (count.branch, count.file, count.count))
+            .collect()
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn every_match_on_a_line_is_counted() {
+        let stdout = b"main:src/a.rs\x001\x00fn\nmain:src/a.rs\x001\x00fn\n\
+                       main:src/a.rs\x004\x00fn\nmain:b\tc.rs\x002\x00fn\n";
+        assert_eq!(
+            counts(&["main"], stdout),
+            [
+                ("main".to_string(), "repo/main:src/a.rs".to_string(), 3),
+                ("main".to_string(), "repo/main:b\tc.rs".to_string(), 1),
+            ]
+        );
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn each_revision_is_counted_apart() {
+        let stdout = b"main:a.rs\x00fn\nv1:a.rs\x00fn\nv1:a.rs\x00fn\n";
+        assert_eq!(
+            counts(&["main", "v1"], stdout),
+            [
+                ("main".to_string(), "repo/main:a.rs".to_string(), 1),
+                ("v1".to_string(), "repo/v1:a.rs".to_string(), 2),
+            ]
+        );
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn totals_have_the_most_matches_first() {
+        let count = |branch: &str, file: &str, count| Count {
+            repo: "repo".to_string(),
+            branch: branch.to_string(),
+            file: file.to_string(),
+            count,
+        };
+        let counts = [
+            count("v1", "a", 1),
+            count("v2", "b", 2),
+            count("v1", "c", 2),
+        ];
+        assert_eq!(
+            totals(&counts, CountBy::Branch),
+            [("v1".to_string(), 3), ("v2".to_string(), 2)]
+        );
+        assert_eq!(
+            totals(&counts, CountBy::File),
+            [
+                ("b".to_string(), 2),
+                ("c".to_string(), 2),
+                ("a".to_string(), 1)
+            ]
+        );
+        assert_eq!(totals(&counts, CountBy::Repo), [("repo".to_string(), 5)]);
+    }
 }
@@ -6,21 +6,20 @@ use crate::result::{get, Result};
 
 //------------------------------------------------------------------------------
 pub fn run(regex: &regex::Regex, branch_regex: &BranchRegex) -> Result<()> {
-    // Filtered traversal
-    if let Some(pattern) = branch_regex {
-        for path in RepoIterator::new(regex) {
-            if filter::branch(&pattern, &path)? {
-                let display = get(path.as_path().to_str())?;
-                println!("{0}", display);
+    // Always sorted, so the listing is the same from one run to the next
+    let mut paths: Vec<_> = RepoIterator::new(regex).collect();
+    paths.sort();
+
+    for path in paths {
+        // Filter based on branch name
+        if let Some(pattern) = branch_regex {
+            if !filter::branch(pattern, &path)? {
+                continue;
             }
         }
 
-    // Unfiltered traversal
-    } else {
-        for path in RepoIterator::new(regex) {
-            let display = get(path.as_path().to_str())?;
-            println!("{0}", display);
-        }
+        let display = get(path.as_path().to_str())?;
+        println!("{0}", display);
     }
 
     Ok(())
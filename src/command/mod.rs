@@ -8,7 +8,11 @@ pub mod grep;
 pub mod ls;
 pub mod ls_files;
 pub mod mv;
+pub mod rename_symbol;
 pub mod replace;
 pub mod reset;
+pub mod restore;
+pub mod show_change;
 pub mod status;
+pub mod undo;
 //------------------------------------------------------------------------------
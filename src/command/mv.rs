@@ -1,55 +1,460 @@
 //------------------------------------------------------------------------------
+use crate::error::Error;
 use crate::git;
-use crate::io::write_to_stderr;
+use crate::io::{argument_error, write_to_stderr};
+use crate::journal::{self, Entry, Journal};
 use crate::path;
-use crate::result::Result;
+use crate::result::{get, Result};
 //------------------------------------------------------------------------------
+use std::collections::HashSet;
 use std::fs;
+use std::io::Write;
 use std::process;
 
 //------------------------------------------------------------------------------
-pub fn run(from: &str, to: &str) -> Result<()> {
-    let mut from_path = path::PathBuf::new();
-    let mut to_path = path::PathBuf::new();
+// Options
+//------------------------------------------------------------------------------
+#[derive(Default)]
+pub struct Options {
+    pub with_history: bool,
+    pub dry_run: bool,
+    pub force: bool,
+    pub sources: Vec<String>,
+    pub dest: String,
+}
+
+//------------------------------------------------------------------------------
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut paths = Vec::new();
+    let mut only_paths = false;
+    for arg in args {
+        if only_paths {
+            paths.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "--with-history" => options.with_history = true,
+            "-n" | "--dry-run" => options.dry_run = true,
+            "-f" | "--force" => options.force = true,
+            "--" => only_paths = true,
+            _ if arg.starts_with('-') => {
+                argument_error(&format!("unknown mv option '{0}'", arg));
+            }
+            _ => paths.push(arg.clone()),
+        }
+    }
 
-    from_path.push(from);
-    to_path.push(to);
+    if paths.len() < 2 {
+        argument_error("mv requires a source and a dest");
+    }
+    options.dest = paths.pop().unwrap_or_default();
+    options.sources = paths;
 
-    let (from_repo, from_rel) = git::relative_to_repo(&mut from_path)?;
-    let (to_repo, to_rel) = git::relative_to_repo(&mut to_path)?;
+    options
+}
 
-    if from_path.exists() {
-        // Remove the destionation if it exists
-        if to_path.exists() {
-            let output = process::Command::new("git")
-                .args(&["rm", "-rf", to_rel.as_str()])
-                .current_dir(to_repo.clone())
-                .output()?;
+//------------------------------------------------------------------------------
+// Move
+//------------------------------------------------------------------------------
+// One source and where it ends up, worked out before anything is touched
+struct Move {
+    from_path: path::PathBuf,
+    to_path: path::PathBuf,
+    from_repo: path::PathBuf,
+    from_rel: String,
+    to_repo: path::PathBuf,
+    to_rel: String,
+    tracked: bool,
+    same_repo: bool,
+}
 
-            write_to_stderr(&to_repo, &output.stderr)?;
+//------------------------------------------------------------------------------
+fn is_tracked(repo: &path::Path, relative_path: &str) -> Result<bool> {
+    let output = process::Command::new("git")
+        .args(["ls-files", "--", relative_path])
+        .current_dir(repo)
+        .output()?;
+
+    Ok(output.status.success() && !output.stdout.is_empty())
+}
+
+//------------------------------------------------------------------------------
+fn exists(path: &path::Path) -> bool {
+    // A broken symlink still needs moving
+    fs::symlink_metadata(path).is_ok()
+}
+
+//------------------------------------------------------------------------------
+fn remove(path: &path::Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// The absolute path of a file, following symlinks up to it but not the file
+// itself, so a broken symlink still has one and a symlinked directory isn't
+// taken for its target
+fn canonical_path(file: &path::Path) -> Result<path::PathBuf> {
+    let parent = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => path::Path::new("."),
+    };
+    match file.file_name() {
+        Some(name) => Ok(fs::canonicalize(parent)?.join(name)),
+        None => Ok(fs::canonicalize(file)?),
+    }
+}
+
+//------------------------------------------------------------------------------
+// Check a source can be moved to the dest, as git mv would
+fn plan_move(
+    options: &Options,
+    source: &str,
+    dest: &path::Path,
+) -> Result<Move> {
+    let from_path = path::PathBuf::from(source);
+    if !exists(&from_path) {
+        return Err(Error::BadSource(from_path));
+    }
+
+    // Moving into a directory keeps the name
+    let mut to_path = dest.to_path_buf();
+    if dest.is_dir() {
+        if let Some(name) = from_path.file_name() {
+            to_path.push(name);
         }
+    }
+
+    let to_parent = match to_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => path::PathBuf::from("."),
+    };
+    if !to_parent.is_dir() {
+        return Err(Error::NotADirectory(to_parent));
+    }
+    if fs::canonicalize(&to_parent)?.starts_with(canonical_path(&from_path)?) {
+        return Err(Error::MoveIntoItself(from_path));
+    }
+
+    let (from_repo, from_rel) = git::relative_to_repo(&from_path)?;
+    let (to_repo, to_rel) = git::relative_to_repo(&to_path)?;
+    if from_rel == "." {
+        return Err(Error::MoveRepo(from_path));
+    }
+
+    // Replaying the history can't write over a file
+    if exists(&to_path) && (!options.force || options.with_history) {
+        return Err(Error::DestinationExists(to_path));
+    }
 
-        // Move the file
-        fs::rename(&from_path, &to_path)?;
+    let tracked = is_tracked(&from_repo, &from_rel)?;
+    let same_repo =
+        fs::canonicalize(&from_repo)? == fs::canonicalize(&to_repo)?;
+    if options.with_history && !same_repo && !tracked {
+        return Err(Error::NoHistory(from_path));
+    }
+
+    Ok(Move {
+        from_path,
+        to_path,
+        from_repo,
+        from_rel,
+        to_repo,
+        to_rel,
+        tracked,
+        same_repo,
+    })
+}
 
-        // Remove the old file or folder
-        {
-            let output = process::Command::new("git")
-                .args(&["rm", "-rf", from_rel.as_str()])
-                .current_dir(from_repo.clone())
-                .output()?;
-            write_to_stderr(&to_repo, &output.stderr)?;
+//------------------------------------------------------------------------------
+// What the journal needs to put a move back, with the repos made absolute
+fn journal_move(planned: &Move) -> Result<journal::Move> {
+    Ok(journal::Move {
+        from_repo: fs::canonicalize(&planned.from_repo)?,
+        from_rel: planned.from_rel.clone(),
+        to_repo: fs::canonicalize(&planned.to_repo)?,
+        to_rel: planned.to_rel.clone(),
+        tracked: planned.tracked,
+        history: None,
+    })
+}
+
+//------------------------------------------------------------------------------
+fn move_files(planned: &Move, journal: &mut Journal) -> Result<()> {
+    let moved = journal_move(planned)?;
+
+    // Remove the destination, it can only exist with -f, keeping it so the
+    // move can be undone. It's journaled before it goes, so even a move that
+    // fails after that can give it back.
+    if exists(&planned.to_path) {
+        let tracked = is_tracked(&planned.to_repo, &planned.to_rel)?;
+        let backup = journal.keep_path(&planned.to_path)?;
+        journal.record(Entry::Overwrite {
+            repo: moved.to_repo.clone(),
+            rel: moved.to_rel.clone(),
+            backup,
+            tracked,
+        })?;
+        if tracked {
+            git::run(
+                &planned.to_repo,
+                &["rm", "-rf", "--quiet", &planned.to_rel],
+            )?;
+        }
+        if exists(&planned.to_path) {
+            remove(&planned.to_path)?;
         }
+    }
 
-        // Add the newfile or folder
-        {
-            let output = process::Command::new("git")
-                .args(&["add", to_rel.as_str()])
-                .current_dir(to_repo.clone())
-                .output()?;
-            write_to_stderr(&to_repo, &output.stderr)?;
+    // Only what git knew about is staged again at the dest, untracked files
+    // in a directory are just moved along with it
+    let tracked_files =
+        git::tracked_files(&planned.from_repo, &planned.from_rel)?;
+
+    // Move the file
+    fs::rename(&planned.from_path, &planned.to_path)?;
+    journal.record(Entry::Move(moved))?;
+
+    // Files git didn't know about are just moved
+    if !planned.tracked {
+        return Ok(());
+    }
+
+    // Remove the old file or folder
+    git::run(
+        &planned.from_repo,
+        &["rm", "-rf", "--cached", "--quiet", &planned.from_rel],
+    )?;
+
+    // Add the new file or folder
+    let to_files: Vec<Vec<u8>> = tracked_files
+        .iter()
+        .map(|file| rewrite_path(file, &planned.from_rel, &planned.to_rel))
+        .collect();
+    git::stage_files(&planned.to_repo, &to_files)?;
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Swap the source path for the dest path, for the file itself or anything
+// under it when it's a directory. Paths are bytes, as git gives them.
+pub fn rewrite_path(file: &[u8], from_rel: &str, to_rel: &str) -> Vec<u8> {
+    match file.strip_prefix(from_rel.as_bytes()) {
+        Some(rest) if rest.is_empty() || rest.starts_with(b"/") => {
+            [to_rel.as_bytes(), rest].concat()
+        }
+        _ => file.to_vec(),
+    }
+}
+
+//------------------------------------------------------------------------------
+// Point the patches from 'git format-patch' at the dest path. Only the diff
+// headers name files, the lines of the hunks are left alone, byte for byte.
+fn rewrite_patches(patches: &[u8], from_rel: &str, to_rel: &str) -> Vec<u8> {
+    let rewrite = |file: &[u8]| rewrite_path(file, from_rel, to_rel);
+
+    let mut rewritten = Vec::new();
+    let mut in_header = false;
+    for line in patches.split_inclusive(|byte| *byte == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let mut replacement = None;
+
+        if let Some(files) = content.strip_prefix(b"diff --git a/") {
+            in_header = true;
+            let b = files.windows(3).rposition(|window| window == b" b/");
+            if let Some(position) = b {
+                replacement = Some(
+                    [
+                        &b"diff --git a/"[..],
+                        &rewrite(&files[..position]),
+                        b" b/",
+                        &rewrite(&files[position + 3..]),
+                    ]
+                    .concat(),
+                );
+            }
+        } else if content.starts_with(b"@@") {
+            in_header = false;
+        } else if in_header {
+            for prefix in [
+                &b"--- a/"[..],
+                b"+++ b/",
+                b"rename from ",
+                b"rename to ",
+                b"copy from ",
+                b"copy to ",
+            ] {
+                if let Some(file) = content.strip_prefix(prefix) {
+                    replacement = Some([prefix, &rewrite(file)].concat());
+                }
+            }
+        }
+
+        match replacement {
+            Some(replacement) => {
+                rewritten.extend_from_slice(&replacement);
+                rewritten.push(b'\n');
+            }
+            None => rewritten.extend_from_slice(line),
+        }
+    }
+    rewritten
+}
+
+//------------------------------------------------------------------------------
+// Replay the commits that touched the source in the dest repo, so its log
+// and blame come along with it. Merges aren't replayed, as 'git
+// format-patch' leaves them out, and nor is anything from before the source
+// was last renamed.
+fn move_with_history(planned: &Move, journal: &mut Journal) -> Result<()> {
+    let from_repo = planned.from_repo.as_path();
+    let to_repo = planned.to_repo.as_path();
+    let from_rel = planned.from_rel.as_str();
+
+    let output = process::Command::new("git")
+        .args([
+            "format-patch",
+            "--keep-subject",
+            "--root",
+            "--stdout",
+            "--binary",
+            "--full-index",
+        ])
+        .args(["HEAD", "--", from_rel])
+        .current_dir(from_repo)
+        .output()?;
+    write_to_stderr(from_repo, &output.stderr)?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(Error::NoHistory(planned.from_path.clone()));
+    }
+
+    // The patches are applied straight to the index, which has to be clean
+    if !git::run(to_repo, &["diff", "--cached", "--quiet"])? {
+        return Err(Error::DirtyIndex(to_repo.to_path_buf()));
+    }
+
+    let patches = rewrite_patches(&output.stdout, from_rel, &planned.to_rel);
+    let previous_head = git::current_head(to_repo)?;
+
+    let mut child = process::Command::new("git")
+        .args(["am", "--keep", "--keep-cr", "--quiet"])
+        .current_dir(to_repo)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    get(child.stdin.take())?.write_all(&patches)?;
+    let output = child.wait_with_output()?;
+    write_to_stderr(to_repo, &output.stderr)?;
+    if !output.status.success() {
+        git::run(to_repo, &["am", "--abort"])?;
+        return Err(Error::UnableToReplayHistory(to_repo.to_path_buf()));
+    }
+
+    // The replayed history ends at the last commit, so swap in the working
+    // copy to keep any changes that weren't committed
+    remove(&planned.to_path)?;
+    fs::rename(&planned.from_path, &planned.to_path)?;
+
+    let mut moved = journal_move(planned)?;
+    moved.history = Some((previous_head, get(git::current_head(to_repo)?)?));
+    journal.record(Entry::Move(moved))?;
+
+    // Remove the old file or folder
+    git::run(from_repo, &["rm", "-r", "--cached", "--quiet", from_rel])?;
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+pub fn run(options: &Options) -> Result<()> {
+    let dest = path::PathBuf::from(&options.dest);
+    if options.sources.len() > 1 && !dest.is_dir() {
+        return Err(Error::NotADirectory(dest));
+    }
+
+    // Check everything first, so a bad source doesn't leave the move half
+    // done
+    let mut moves = Vec::new();
+    for source in &options.sources {
+        moves.push(plan_move(options, source, &dest)?);
+    }
+
+    // Two sources with the same name would write over each other
+    let mut targets = HashSet::new();
+    for planned in &moves {
+        if !targets.insert(canonical_path(&planned.to_path)?) {
+            return Err(Error::MultipleSources(planned.to_path.clone()));
+        }
+    }
+
+    let mut journal = Journal::new("mv");
+    for planned in &moves {
+        if options.dry_run {
+            println!(
+                "Renaming {0} to {1}",
+                planned.from_path.display(),
+                planned.to_path.display()
+            );
+        } else if options.with_history && !planned.same_repo {
+            move_with_history(planned, &mut journal)?;
+        } else {
+            move_files(planned, &mut journal)?;
         }
     }
 
     Ok(())
 }
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn rewrite_path_only_matches_whole_components() {
+        assert_eq!(rewrite_path(b"src", "src", "lib"), b"lib");
+        assert_eq!(rewrite_path(b"src/a.rs", "src", "lib"), b"lib/a.rs");
+        assert_eq!(rewrite_path(b"srcx/a.rs", "src", "lib"), b"srcx/a.rs");
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn rewrite_patches_only_touches_the_headers() {
+        let patches = b"Subject: [PATCH] move src/a.rs\n\
+            \n\
+            diff --git a/src/a.rs b/src/a.rs\n\
+            index 1111111..2222222 100644\n\
+            --- a/src/a.rs\n\
+            +++ b/src/a.rs\n\
+            @@ -1 +1 @@\n\
+            --- a/src/a.rs\n\
+            +caf\xe9\r\n\
+            diff --git a/src/b.rs b/src/c.rs\n\
+            rename from src/b.rs\n\
+            rename to src/c.rs\n";
+
+        let expected = b"Subject: [PATCH] move src/a.rs\n\
+            \n\
+            diff --git a/lib/a.rs b/lib/a.rs\n\
+            index 1111111..2222222 100644\n\
+            --- a/lib/a.rs\n\
+            +++ b/lib/a.rs\n\
+            @@ -1 +1 @@\n\
+            --- a/src/a.rs\n\
+            +caf\xe9\r\n\
+            diff --git a/lib/b.rs b/lib/c.rs\n\
+            rename from lib/b.rs\n\
+            rename to lib/c.rs\n";
+
+        assert_eq!(rewrite_patches(patches, "src", "lib"), expected.to_vec());
+    }
+}
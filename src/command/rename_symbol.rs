@@ -0,0 +1,247 @@
+//------------------------------------------------------------------------------
+use crate::branch_regex::BranchRegex;
+use crate::command::replace;
+use crate::edit;
+use crate::execute;
+use crate::filetype;
+use crate::io::{argument_error, write_to_buffer};
+use crate::journal::{self, Entry, Journal};
+use crate::path;
+use crate::result::Result;
+//------------------------------------------------------------------------------
+use std::fs;
+use std::io::Write;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone, Default)]
+pub struct Options {
+    pub from: String,
+    pub to: String,
+    pub dry_run: bool,
+    pub selection: filetype::Selection,
+}
+
+//------------------------------------------------------------------------------
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut positional = Vec::new();
+    let mut only_pathspecs = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+
+        if only_pathspecs {
+            options.selection.pathspecs.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "-n" | "--dry-run" => options.dry_run = true,
+            "--type" => {
+                if i == args.len() {
+                    argument_error("--type requires a type");
+                }
+                options.selection.add_type(&args[i]);
+                i += 1;
+            }
+            "--" => only_pathspecs = true,
+            _ if arg.starts_with("--type=") => {
+                options.selection.add_type(&arg["--type=".len()..]);
+            }
+            _ if arg.starts_with('-') => {
+                argument_error(&format!(
+                    "unknown rename-symbol option '{0}'",
+                    arg
+                ));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if positional.len() != 2 {
+        argument_error("rename-symbol requires a from and a to");
+    }
+    options.to = positional.remove(1);
+    options.from = positional.remove(0);
+
+    options
+}
+
+//------------------------------------------------------------------------------
+// The symbol is only ever matched literally, as a whole word, in file names
+// and in the files themselves
+fn replace_options(options: &Options) -> replace::Options {
+    replace::Options {
+        from: options.from.clone(),
+        to: options.to.clone(),
+        dry_run: options.dry_run,
+        fixed_strings: true,
+        word: true,
+        selection: options.selection.clone(),
+        ..replace::Options::default()
+    }
+}
+
+//------------------------------------------------------------------------------
+// The file with the symbol renamed in its name, None when its name doesn't
+// have the symbol. Only the name changes, never the directories it's in.
+fn renamed(matcher: &replace::Matcher, file: &str) -> Option<String> {
+    let (dir, name) = match file.rfind('/') {
+        Some(slash) => file.split_at(slash + 1),
+        None => ("", file),
+    };
+
+    let mut edits = Vec::new();
+    matcher.find(name.as_bytes(), 0, &mut edits);
+    if edits.is_empty() {
+        return None;
+    }
+    let name = edit::apply(name.as_bytes(), &edits);
+    Some(format!("{0}{1}", dir, String::from_utf8_lossy(&name)))
+}
+
+//------------------------------------------------------------------------------
+// Rename the files with the symbol in their name, returning what was renamed
+// and whether everything that should have been could be
+fn rename_files(
+    path: &path::Path,
+    matcher: &replace::Matcher,
+    options: &Options,
+    task_options: &execute::Options,
+    journal: &Mutex<Journal>,
+    out: &mut execute::Output,
+) -> Result<(Vec<(String, String)>, bool)> {
+    let files = match options.selection.list_files(path, out)? {
+        Some(files) => files,
+        None => return Ok((Vec::new(), false)),
+    };
+
+    let mut renames = Vec::new();
+    let mut all_renamed = true;
+    for file in files {
+        let new_file = match renamed(matcher, &file) {
+            Some(new_file) => new_file,
+            None => continue,
+        };
+
+        if fs::symlink_metadata(path.join(&new_file)).is_ok() {
+            let message = format!(
+                "not renaming '{0}', '{1}' already exists\n",
+                file, new_file
+            );
+            write_to_buffer(&mut out.stderr, path, message.as_bytes())?;
+            all_renamed = false;
+            continue;
+        }
+
+        if !options.dry_run {
+            let mut command = process::Command::new("git");
+            command
+                .args(["mv", "--", &file, &new_file])
+                .current_dir(path);
+            let output = execute::run_in_repo(
+                path,
+                &mut command,
+                task_options,
+                execute::Capture::Keep,
+                out,
+            )?;
+            if output.outcome != execute::Outcome::Success {
+                all_renamed = false;
+                continue;
+            }
+
+            let repo = fs::canonicalize(path)?;
+            journal.lock()?.record(Entry::Move(journal::Move {
+                from_repo: repo.clone(),
+                from_rel: file.clone(),
+                to_repo: repo,
+                to_rel: new_file.clone(),
+                tracked: true,
+                history: None,
+            }))?;
+        }
+        renames.push((file, new_file));
+    }
+
+    Ok((renames, all_renamed))
+}
+
+//------------------------------------------------------------------------------
+fn doit(
+    path: &path::Path,
+    options: &Options,
+    task_options: &execute::Options,
+    journal: &Arc<Mutex<Journal>>,
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let replace_options = replace_options(options);
+    let matcher = replace::Matcher::new(&replace_options)?;
+
+    // The files are renamed first, so the references are rewritten in them
+    // wherever they've ended up
+    let (renames, all_renamed) =
+        rename_files(path, &matcher, options, task_options, journal, out)?;
+
+    let files = match replace::candidate_files(path, &options.selection, out)? {
+        Some(files) => files,
+        None => return Ok(execute::Outcome::Failed(None)),
+    };
+    let journal = match options.dry_run {
+        true => None,
+        false => Some(journal),
+    };
+    let changes = replace::replace_in_files(&matcher, files, journal)?;
+
+    let mut report = Vec::new();
+    let verb = match options.dry_run {
+        true => "would rename",
+        false => "renamed",
+    };
+    for (file, new_file) in &renames {
+        writeln!(report, "{0} '{1}' to '{2}'", verb, file, new_file)?;
+    }
+    if options.dry_run && !changes.is_empty() {
+        replace::write_diffs(&mut report, &changes)?;
+    } else if !changes.is_empty() {
+        let replacements: usize =
+            changes.iter().map(|change| change.edits.len()).sum();
+        writeln!(
+            report,
+            "{0} replacement(s) in {1} file(s)",
+            replacements,
+            changes.len()
+        )?;
+    }
+    write_to_buffer(&mut out.stdout, path, &report)?;
+
+    match all_renamed {
+        true => Ok(execute::Outcome::Success),
+        false => Ok(execute::Outcome::Failed(None)),
+    }
+}
+
+//------------------------------------------------------------------------------
+pub fn run(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    execute_options: &execute::Options,
+    options: &Options,
+) -> Result<()> {
+    let options = options.clone();
+
+    let task_options = execute_options.clone();
+
+    let journal = Arc::new(Mutex::new(Journal::new("rename-symbol")));
+    execute::for_each_repo(
+        regex,
+        branch_regex,
+        execute_options,
+        move |path, out| doit(path, &options, &task_options, &journal, out),
+    )
+}
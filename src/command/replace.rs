@@ -1,122 +1,443 @@
 //------------------------------------------------------------------------------
 use crate::branch_regex::BranchRegex;
-use crate::filter;
-use crate::io::write_to_stderr;
+use crate::edit;
+use crate::execute;
+use crate::filetype;
+use crate::io::{argument_error, write_atomically, write_to_buffer};
+use crate::journal::{self, Entry, Journal};
+use crate::patch;
 use crate::path;
-use crate::repoiterator::RepoIterator;
-use crate::result::{handle_errors, Result};
+use crate::pathspec;
+use crate::result::Result;
 //------------------------------------------------------------------------------
-use std::env;
+use colored::*;
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::process;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 //------------------------------------------------------------------------------
-fn replace_in_file(
-    from_regex: &regex::Regex,
-    to_regex: &str,
-    file_path: &path::Path,
-) -> Result<()> {
-    let mut output = Vec::<u8>::new();
-    {
-        let full_path = path::PathBuf::from(file_path);
-        let input = fs::File::open(full_path.as_path())?;
-        let buffered = BufReader::new(input);
-        for line in buffered.lines() {
-            let old_line = line?;
-            let new_line =
-                from_regex.replace_all(&old_line as &str, to_regex as &str);
-            writeln!(output, "{0}", new_line)?;
+const INTERACTIVE_HELP: &str = "y - replace this match
+n - do not replace this match
+q - quit; do not replace this match or any of the remaining ones
+a - replace this match and all later matches in the file
+d - do not replace this match or any of the later matches in the file
+? - print help";
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone, Default)]
+pub struct Options {
+    pub from: String,
+    pub to: String,
+    pub dry_run: bool,
+    pub interactive: bool,
+    pub multiline: bool,
+    pub fixed_strings: bool,
+    pub ignore_case: bool,
+    pub word: bool,
+    pub selection: filetype::Selection,
+}
+
+//------------------------------------------------------------------------------
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut positional = Vec::new();
+    let mut only_pathspecs = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+
+        if only_pathspecs {
+            options.selection.pathspecs.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "-n" | "--dry-run" => options.dry_run = true,
+            "-i" | "--interactive" => options.interactive = true,
+            "-U" | "--multiline" => options.multiline = true,
+            "-F" | "--fixed-strings" => options.fixed_strings = true,
+            "--ignore-case" => options.ignore_case = true,
+            "-w" | "--word" => options.word = true,
+            "--type" => {
+                if i == args.len() {
+                    argument_error("--type requires a type");
+                }
+                options.selection.add_type(&args[i]);
+                i += 1;
+            }
+            "--" => only_pathspecs = true,
+            _ if arg.starts_with("--type=") => {
+                options.selection.add_type(&arg["--type=".len()..]);
+            }
+            _ if arg.starts_with('-') && positional.len() < 2 => {
+                argument_error(&format!("unknown replace option '{0}'", arg));
+            }
+            _ => positional.push(arg.clone()),
         }
     }
-    let mut input = fs::File::create(file_path)?;
-    input.write_all(&output)?;
 
-    Ok(())
+    if positional.len() != 2 {
+        argument_error("replace requires a from and a to");
+    }
+    if options.dry_run && options.interactive {
+        argument_error("--dry-run and --interactive can't be used together");
+    }
+    options.to = positional.remove(1);
+    options.from = positional.remove(0);
+
+    options
 }
 
 //------------------------------------------------------------------------------
-fn doit(
-    branch_filter: &BranchRegex,
-    path: &path::PathBuf,
-    from: &str,
-    to: &str,
-) -> Result<()> {
-    // Filter based on branch name
-    if let Some(pattern) = branch_filter {
-        if !filter::branch(&pattern, &path)? {
-            return Ok(());
+// Matcher
+//------------------------------------------------------------------------------
+// The pattern as the options ask for it. It's used both to find the files
+// and to replace in them, so they can't disagree about what matches.
+#[derive(Clone)]
+pub struct Matcher {
+    regex: regex::bytes::Regex,
+    to: Vec<u8>,
+    literal: bool,
+    multiline: bool,
+}
+
+//------------------------------------------------------------------------------
+impl Matcher {
+    pub fn new(options: &Options) -> Result<Self> {
+        let mut pattern = if options.fixed_strings {
+            regex::escape(&options.from)
+        } else {
+            options.from.clone()
+        };
+        if options.word {
+            pattern = format!(r"\b(?:{0})\b", pattern);
+        }
+
+        // ^ and $ match at the start and end of every line when the whole
+        // file is searched at once, \r\n counting as a line ending
+        let regex = regex::bytes::RegexBuilder::new(&pattern)
+            .case_insensitive(options.ignore_case)
+            .multi_line(options.multiline)
+            .crlf(options.multiline)
+            .build()?;
+
+        Ok(Matcher {
+            regex,
+            to: options.to.as_bytes().to_vec(),
+            literal: options.fixed_strings,
+            multiline: options.multiline,
+        })
+    }
+
+    // The edits for the matches in part of the file, which starts at offset
+    pub fn find(
+        &self,
+        text: &[u8],
+        offset: usize,
+        edits: &mut Vec<edit::Edit>,
+    ) {
+        for captures in self.regex.captures_iter(text) {
+            let found = match captures.get(0) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            // A fixed string's replacement is taken literally too
+            let mut replacement = Vec::new();
+            if self.literal {
+                replacement.extend_from_slice(&self.to);
+            } else {
+                captures.expand(&self.to, &mut replacement);
+            }
+
+            if replacement != found.as_bytes() {
+                edits.push(edit::Edit {
+                    start: offset + found.start(),
+                    end: offset + found.end(),
+                    replacement,
+                });
+            }
         }
     }
+}
+
+//------------------------------------------------------------------------------
+// As with git, a file with a NUL byte near the start is taken to be binary
+const BINARY_CHECK_LENGTH: usize = 8000;
+
+//------------------------------------------------------------------------------
+// FileChange
+//------------------------------------------------------------------------------
+// The replacements to make in one file
+pub struct FileChange {
+    pub path: path::PathBuf,
+    pub text: Vec<u8>,
+    pub edits: Vec<edit::Edit>,
+}
 
-    let from_exp = regex::Regex::new(&from)?;
-
-    let args = ["grep", "-l", from];
-    let output = process::Command::new("git")
-        .args(&args)
-        .current_dir(path.clone())
-        .output()?;
-
-    // stderr
-    write_to_stderr(&path, &output.stderr)?;
-
-    // perform the find and replace
-    if !output.stdout.is_empty() {
-        let mut replace_threads = Vec::new();
-        let stdout = BufReader::new(&output.stdout as &[u8]);
-        for line in stdout.lines() {
-            let file_path = path::Path::new(&path).join(line?);
-            let from_regex = from_exp.clone();
-            let to_regex = String::from(to);
-            let replace_thread = thread::spawn(move || {
-                handle_errors(replace_in_file(
-                    &from_regex,
-                    &to_regex,
-                    &file_path,
-                ))
-            });
-
-            replace_threads.push(replace_thread);
+//------------------------------------------------------------------------------
+impl FileChange {
+    // Write the file, keeping what was there in the journal so it can be
+    // undone
+    fn write(
+        &self,
+        edits: &[edit::Edit],
+        journal: &Mutex<Journal>,
+    ) -> Result<()> {
+        let edited = edit::apply(&self.text, edits);
+        if edited == self.text {
+            return Ok(());
         }
 
-        // Wait for all the replace threads to finish
-        for replace_thread in replace_threads {
-            replace_thread.join()?;
+        let backup = journal.lock()?.keep(&self.text)?;
+        write_atomically(&self.path, &edited)?;
+        journal.lock()?.record(Entry::Write {
+            path: fs::canonicalize(&self.path)?,
+            backup,
+            hash: journal::hash(&edited),
+        })
+    }
+}
+
+//------------------------------------------------------------------------------
+fn is_binary(text: &[u8]) -> bool {
+    text.iter().take(BINARY_CHECK_LENGTH).any(|byte| *byte == 0)
+}
+
+//------------------------------------------------------------------------------
+// The matches in the file, each with the bytes to put in its place. Unless
+// it's multiline, the pattern is matched a line at a time. Binary files and
+// symlinks are left alone, as are matches the replacement wouldn't change.
+fn find_edits(matcher: &Matcher, file_path: &path::Path) -> Result<FileChange> {
+    let mut change = FileChange {
+        path: file_path.to_path_buf(),
+        text: Vec::new(),
+        edits: Vec::new(),
+    };
+
+    if fs::symlink_metadata(file_path)?.file_type().is_symlink() {
+        return Ok(change);
+    }
+    change.text = fs::read(file_path)?;
+    if is_binary(&change.text) {
+        return Ok(change);
+    }
+
+    if matcher.multiline {
+        matcher.find(&change.text, 0, &mut change.edits);
+    } else {
+        let mut offset = 0;
+        for line in change.text.split_inclusive(|byte| *byte == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            let content = content.strip_suffix(b"\r").unwrap_or(content);
+            matcher.find(content, offset, &mut change.edits);
+            offset += line.len();
         }
     }
 
+    Ok(change)
+}
+
+//------------------------------------------------------------------------------
+// The files in the repo that might have a match, which is every file git
+// tracks that's been selected
+pub fn candidate_files(
+    path: &path::Path,
+    selection: &filetype::Selection,
+    out: &mut execute::Output,
+) -> Result<Option<Vec<path::PathBuf>>> {
+    let files = selection.list_files(path, out)?.map(|files| {
+        files
+            .iter()
+            .map(|file| path.join(file))
+            .filter(|file| file.is_file())
+            .collect()
+    });
+    Ok(files)
+}
+
+//------------------------------------------------------------------------------
+// Find the edits in each file, and make them unless it's a dry run, which
+// has no journal. The files are shared out between a thread per core, and
+// only the ones with something to replace are returned, in the order they
+// were given.
+pub fn replace_in_files(
+    matcher: &Matcher,
+    files: Vec<path::PathBuf>,
+    journal: Option<&Arc<Mutex<Journal>>>,
+) -> Result<Vec<FileChange>> {
+    let threads_wanted = thread::available_parallelism().map_or(4, |n| n.get());
+    let chunk_size = files.len().div_ceil(threads_wanted).max(1);
+
+    let mut threads = Vec::new();
+    for chunk in files.chunks(chunk_size) {
+        let matcher = matcher.clone();
+        let chunk = chunk.to_vec();
+        let journal = journal.cloned();
+        threads.push(thread::spawn(move || -> Result<Vec<FileChange>> {
+            let mut changes = Vec::new();
+            for file_path in chunk {
+                let change = find_edits(&matcher, &file_path)?;
+                if change.edits.is_empty() {
+                    continue;
+                }
+                if let Some(journal) = &journal {
+                    change.write(&change.edits, journal)?;
+                }
+                changes.push(change);
+            }
+            Ok(changes)
+        }));
+    }
+
+    // Wait for all the threads to finish, keeping the files in order
+    let mut changes = Vec::new();
+    for thread in threads {
+        changes.append(&mut thread.join()??);
+    }
+    Ok(changes)
+}
+
+//------------------------------------------------------------------------------
+// Each file's replacements as a diff, then how many there were in all
+pub fn write_diffs(buffer: &mut Vec<u8>, changes: &[FileChange]) -> Result<()> {
+    let mut replacements = 0;
+    for change in changes {
+        let display = change.path.display().to_string();
+        writeln!(
+            buffer,
+            "{0}",
+            format!("{0}: {1} replacement(s)", display, change.edits.len())
+                .bold()
+        )?;
+        let diff_path = path::normalize(&change.path);
+        edit::write_diff(
+            buffer,
+            &diff_path.display().to_string(),
+            &change.text,
+            &change.edits,
+        )?;
+        writeln!(buffer)?;
+        replacements += change.edits.len();
+    }
+    writeln!(
+        buffer,
+        "{0} replacement(s) in {1} file(s)",
+        replacements,
+        changes.len()
+    )?;
     Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn run(
+fn doit(
+    path: &path::Path,
+    options: &Options,
+    journal: &Arc<Mutex<Journal>>,
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let matcher = Matcher::new(options)?;
+
+    let files = match candidate_files(path, &options.selection, out)? {
+        Some(files) => files,
+        None => return Ok(execute::Outcome::Failed(None)),
+    };
+    let journal = match options.dry_run {
+        true => None,
+        false => Some(journal),
+    };
+    let changes = replace_in_files(&matcher, files, journal)?;
+
+    if options.dry_run && !changes.is_empty() {
+        let mut diff = Vec::new();
+        write_diffs(&mut diff, &changes)?;
+        write_to_buffer(&mut out.stdout, path, &diff)?;
+    }
+
+    Ok(execute::Outcome::Success)
+}
+
+//------------------------------------------------------------------------------
+// Walk through every match in every repo in one session, making the chosen
+// replacements a file at a time
+fn replace_interactive(
     regex: &regex::Regex,
     branch_regex: &BranchRegex,
-    args_pos: usize,
+    options: &Options,
 ) -> Result<()> {
-    let mut threads = Vec::new();
+    let matcher = Matcher::new(options)?;
+    let journal = Mutex::new(Journal::new("replace"));
 
-    let args: Vec<String> = env::args().collect();
+    // Gather everything up front, so the matches can be counted
+    let mut changes = Vec::new();
+    for repo in pathspec::filtered_repos(regex, branch_regex)? {
+        let mut out = execute::Output::default();
+        let files = candidate_files(&repo, &options.selection, &mut out)?;
+        std::io::stderr().write_all(&out.stderr)?;
 
-    // Loop through the results of what the walker is outputting
-    for path in RepoIterator::new(regex) {
-        // Get hold of the from and to
-        let from = args[args_pos + 1].clone();
-        let to = args[args_pos + 2].clone();
-        let branch_filter = branch_regex.clone();
+        let mut found =
+            replace_in_files(&matcher, files.unwrap_or_default(), None)?;
+        changes.append(&mut found);
+    }
 
-        // Execute a new thread for processing this result
-        let thread = thread::spawn(move || {
-            handle_errors(doit(&branch_filter, &path, &from, &to))
-        });
-        threads.push(thread);
+    let total: usize = changes.iter().map(|change| change.edits.len()).sum();
+    if total == 0 {
+        println!("No matches.");
+        return Ok(());
     }
 
-    // Wait for all the threads to finish
-    for thread in threads {
-        thread.join()?;
+    let mut chooser =
+        patch::Chooser::new("Replace this match", INTERACTIVE_HELP, total);
+    for change in &changes {
+        let chosen = chooser.choose(change.edits.len(), |index| {
+            let edit = &change.edits[index];
+            println!("{0}", change.path.display().to_string().bold());
+            for hunk in edit::hunks(&change.text, std::slice::from_ref(edit), 3)
+            {
+                patch::print_hunk(&hunk)?;
+            }
+            Ok(())
+        })?;
+
+        // Write as we go, so quitting keeps what was chosen so far
+        let edits: Vec<edit::Edit> = change
+            .edits
+            .iter()
+            .zip(chosen.iter())
+            .filter(|(_, chosen)| **chosen)
+            .map(|(edit, _)| edit.clone())
+            .collect();
+        change.write(&edits, &journal)?;
+
+        if chooser.quit {
+            break;
+        }
     }
 
     Ok(())
 }
+
+//------------------------------------------------------------------------------
+pub fn run(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    execute_options: &execute::Options,
+    options: &Options,
+) -> Result<()> {
+    if options.interactive {
+        return replace_interactive(regex, branch_regex, options);
+    }
+
+    let options = options.clone();
+    let journal = Arc::new(Mutex::new(Journal::new("replace")));
+    execute::for_each_repo(
+        regex,
+        branch_regex,
+        execute_options,
+        move |path, out| doit(path, &options, &journal, out),
+    )
+}
@@ -1,43 +1,191 @@
 //------------------------------------------------------------------------------
 use crate::branch_regex::BranchRegex;
-use crate::filter;
-use crate::io::{write_to_stderr, write_to_stdout};
+use crate::execute;
+use crate::io::{argument_error, prompt};
 use crate::path;
-use crate::repoiterator::RepoIterator;
+use crate::pathspec;
 use crate::result::Result;
 //------------------------------------------------------------------------------
 use std::process;
 
 //------------------------------------------------------------------------------
-fn reset_all(path: path::PathBuf) -> Result<()> {
-    let output = process::Command::new("git")
-        .args(&["reset"])
-        .current_dir(path.clone())
-        .output()?;
-
-    // stdout/stderr
-    write_to_stdout(&path, &output.stdout)?;
-    write_to_stderr(&path, &output.stderr)?;
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone, Default)]
+pub struct Options {
+    pub mode: Option<String>,
+    pub commit: Option<String>,
+    pub pathspecs: Vec<String>,
+    pub quiet: bool,
+    pub yes: bool,
+}
 
-    Ok(())
+//------------------------------------------------------------------------------
+impl Options {
+    fn git_args(&self) -> Vec<String> {
+        let mut args = vec!["reset".to_string()];
+        if let Some(mode) = &self.mode {
+            args.push(mode.clone());
+        }
+        if self.quiet {
+            args.push("--quiet".to_string());
+        }
+        if let Some(commit) = &self.commit {
+            args.push(commit.clone());
+        }
+        args
+    }
 }
 
 //------------------------------------------------------------------------------
-pub fn run(regex: &regex::Regex, branch_regex: &BranchRegex) -> Result<()> {
-    // Filtered traversal
-    if let Some(pattern) = branch_regex {
-        for path in RepoIterator::new(regex) {
-            if filter::branch(&pattern, &path)? {
-                reset_all(path)?;
+// Split the arguments after 'reset' up. As with git, a commit can only come
+// first, and without '--' it's taken to be a commit when it isn't a glob
+// and there's no such path.
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut positional = Vec::new();
+    let mut separator = None;
+    for arg in args {
+        if separator.is_some() {
+            positional.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "--soft" | "--mixed" | "--hard" => {
+                options.mode = Some(arg.clone());
+            }
+            "-q" | "--quiet" => options.quiet = true,
+            "-y" | "--yes" => options.yes = true,
+            "--" => separator = Some(positional.len()),
+            _ if arg.starts_with('-') => {
+                argument_error(&format!("unknown reset option '{0}'", arg));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let commits = match separator {
+        Some(position) => position,
+        None => match positional.first() {
+            Some(first)
+                if !pathspec::is_glob(first)
+                    && !path::Path::new(first).exists() =>
+            {
+                1
+            }
+            _ => 0,
+        },
+    };
+    if commits > 1 {
+        argument_error("reset takes at most one commit before '--'");
+    }
+    if commits == 1 {
+        options.commit = Some(positional.remove(0));
+    }
+    options.pathspecs = positional;
+
+    if !options.pathspecs.is_empty() {
+        if let Some(mode) = &options.mode {
+            if mode != "--mixed" {
+                argument_error(&format!(
+                    "Cannot do a {0} reset with paths.",
+                    &mode[2..]
+                ));
             }
         }
+    }
+
+    options
+}
+
+//------------------------------------------------------------------------------
+// A hard reset throws away uncommitted work, so check first
+fn confirm_hard(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    options: &Options,
+) -> Result<bool> {
+    let repos = pathspec::filtered_repos(regex, branch_regex)?;
+    if repos.is_empty() {
+        return Ok(false);
+    }
+
+    println!(
+        "This discards all uncommitted changes to tracked files in {0} repo(s):",
+        repos.len()
+    );
+    for repo in &repos {
+        println!("    {0}", repo.display());
+    }
 
-    // Unfiltered traversal
-    } else {
-        for path in RepoIterator::new(regex) {
-            reset_all(path)?;
+    let question = format!(
+        "Reset them to {0}? [y/N] ",
+        options.commit.as_deref().unwrap_or("HEAD")
+    );
+    match prompt(&question)?.as_deref() {
+        Some("y") | Some("yes") => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+//------------------------------------------------------------------------------
+fn doit(
+    path: &path::Path,
+    options: &execute::Options,
+    args: &[String],
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let mut command = process::Command::new("git");
+    command.args(args).current_dir(path);
+
+    let finished = execute::run_in_repo(
+        path,
+        &mut command,
+        options,
+        execute::Capture::Print,
+        out,
+    )?;
+    Ok(finished.outcome)
+}
+
+//------------------------------------------------------------------------------
+pub fn run(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    execute_options: &execute::Options,
+    options: &Options,
+) -> Result<()> {
+    let task_options = execute_options.clone();
+    let args = options.git_args();
+
+    // Without a pathspec the whole of every repo is reset
+    if options.pathspecs.is_empty() {
+        if options.mode.as_deref() == Some("--hard")
+            && !options.yes
+            && !confirm_hard(regex, branch_regex, options)?
+        {
+            println!("Nothing was reset.");
+            return Ok(());
         }
+
+        return execute::for_each_repo(
+            regex,
+            branch_regex,
+            execute_options,
+            move |path, out| doit(path, &task_options, &args, out),
+        );
     }
 
-    Ok(())
+    // Otherwise each repo unstages the pathspecs that fall inside it
+    let per_repo = pathspec::resolve(regex, branch_regex, &options.pathspecs)?;
+    let repos: Vec<path::PathBuf> = per_repo.keys().cloned().collect();
+    execute::for_each(repos, &None, execute_options, move |path, out| {
+        let mut repo_args = args.clone();
+        repo_args.push("--".to_string());
+        if let Some(specs) = per_repo.get(path) {
+            repo_args.extend(specs.iter().cloned());
+        }
+        doit(path, &task_options, &repo_args, out)
+    })
 }
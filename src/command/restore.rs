@@ -0,0 +1,212 @@
+//------------------------------------------------------------------------------
+use crate::branch_regex::BranchRegex;
+use crate::execute;
+use crate::io::argument_error;
+use crate::path;
+use crate::pathspec;
+use crate::result::Result;
+//------------------------------------------------------------------------------
+use std::io::Write;
+use std::process;
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone, Default)]
+pub struct Options {
+    pub staged: bool,
+    pub worktree: bool,
+    pub source: Option<String>,
+    pub dry_run: bool,
+    pub pathspecs: Vec<String>,
+}
+
+//------------------------------------------------------------------------------
+impl Options {
+    fn git_args(&self) -> Vec<String> {
+        let mut args = vec!["restore".to_string()];
+        if self.staged {
+            args.push("--staged".to_string());
+        }
+        if self.worktree {
+            args.push("--worktree".to_string());
+        }
+        if let Some(source) = &self.source {
+            args.push(format!("--source={0}", source));
+        }
+        args
+    }
+
+    // The diffs that show what would be restored, as with git, --staged on
+    // its own leaves the working tree alone
+    fn diff_args(&self) -> Vec<Vec<String>> {
+        let source: Vec<String> = self.source.iter().cloned().collect();
+
+        let mut diffs = Vec::new();
+        if self.staged {
+            diffs.push(
+                ["diff", "--name-only", "-z", "--cached"]
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .chain(source.iter().cloned())
+                    .collect(),
+            );
+        }
+        if self.worktree || !self.staged {
+            diffs.push(
+                ["diff", "--name-only", "-z"]
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .chain(source.iter().cloned())
+                    .collect(),
+            );
+        }
+        diffs
+    }
+}
+
+//------------------------------------------------------------------------------
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+
+    let mut only_pathspecs = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+
+        if only_pathspecs {
+            options.pathspecs.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "-S" | "--staged" => options.staged = true,
+            "-W" | "--worktree" => options.worktree = true,
+            "-n" | "--dry-run" => options.dry_run = true,
+            "-s" | "--source" => {
+                if i == args.len() {
+                    argument_error("--source requires a tree");
+                }
+                options.source = Some(args[i].clone());
+                i += 1;
+            }
+            "--" => only_pathspecs = true,
+            _ if arg.starts_with("--source=") => {
+                options.source = Some(arg["--source=".len()..].to_string());
+            }
+            _ if arg.starts_with('-') => {
+                argument_error(&format!("unknown restore option '{0}'", arg));
+            }
+            _ => options.pathspecs.push(arg.clone()),
+        }
+    }
+
+    if options.pathspecs.is_empty() {
+        argument_error("you must specify path(s) to restore");
+    }
+
+    options
+}
+
+//------------------------------------------------------------------------------
+// The files with changes that restoring would discard. A glob is matched
+// against untracked files too, and git restore refuses any pathspec that
+// doesn't match a file it knows about, so only these are given to it. They
+// are NUL separated so a name git would quote comes back as it is.
+fn changed_files(
+    path: &path::Path,
+    execute_options: &execute::Options,
+    options: &Options,
+    specs: &[String],
+    out: &mut execute::Output,
+) -> Result<(execute::Outcome, Vec<String>)> {
+    let mut files: Vec<String> = Vec::new();
+    for diff in options.diff_args() {
+        let mut command = process::Command::new("git");
+        command.args(diff).arg("--").args(specs).current_dir(path);
+        let output = execute::run_in_repo(
+            path,
+            &mut command,
+            execute_options,
+            execute::Capture::Keep,
+            out,
+        )?;
+        if !matches!(output.outcome, execute::Outcome::Success) {
+            return Ok((output.outcome, files));
+        }
+
+        let names = output.stdout.split(|byte| *byte == 0);
+        for name in names.filter(|name| !name.is_empty()) {
+            let name = String::from_utf8_lossy(name).to_string();
+            if !files.contains(&name) {
+                files.push(name);
+            }
+        }
+    }
+
+    Ok((execute::Outcome::Success, files))
+}
+
+//------------------------------------------------------------------------------
+fn doit(
+    path: &path::Path,
+    execute_options: &execute::Options,
+    options: &Options,
+    specs: &[String],
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let (outcome, files) =
+        changed_files(path, execute_options, options, specs, out)?;
+    if !matches!(outcome, execute::Outcome::Success) || files.is_empty() {
+        return Ok(outcome);
+    }
+
+    // List them as paths from the current directory
+    if options.dry_run {
+        let flat_path = path.join(path::Path::new(""));
+        for file in files {
+            writeln!(
+                out.stdout,
+                "would restore '{0}{1}'",
+                flat_path.display(),
+                file
+            )?;
+        }
+        return Ok(outcome);
+    }
+
+    let mut command = process::Command::new("git");
+    command
+        .args(options.git_args())
+        .arg("--")
+        .args(files)
+        .current_dir(path);
+
+    let finished = execute::run_in_repo(
+        path,
+        &mut command,
+        execute_options,
+        execute::Capture::Print,
+        out,
+    )?;
+    Ok(finished.outcome)
+}
+
+//------------------------------------------------------------------------------
+pub fn run(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    execute_options: &execute::Options,
+    options: &Options,
+) -> Result<()> {
+    let task_options = execute_options.clone();
+    let options = options.clone();
+
+    // Each repo restores the pathspecs that fall inside it
+    let per_repo = pathspec::resolve(regex, branch_regex, &options.pathspecs)?;
+    let repos: Vec<path::PathBuf> = per_repo.keys().cloned().collect();
+    execute::for_each(repos, &None, execute_options, move |path, out| {
+        let specs = per_repo.get(path).cloned().unwrap_or_default();
+        doit(path, &task_options, &options, &specs, out)
+    })
+}
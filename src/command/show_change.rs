@@ -0,0 +1,55 @@
+//------------------------------------------------------------------------------
+use crate::branch_regex::BranchRegex;
+use crate::command::commit::CHANGE_ID_TRAILER;
+use crate::execute;
+use crate::io::write_to_buffer;
+use crate::path;
+use crate::result::Result;
+//------------------------------------------------------------------------------
+use std::process;
+
+//------------------------------------------------------------------------------
+fn doit(
+    options: &execute::Options,
+    id: &str,
+    log_args: &[String],
+    path: &path::Path,
+    out: &mut execute::Output,
+) -> Result<execute::Outcome> {
+    let grep = format!("--grep=^{0}: {1}$", CHANGE_ID_TRAILER, id);
+
+    let mut command = process::Command::new("git");
+    command
+        .args(["log", "--all", &grep])
+        .args(log_args)
+        .current_dir(path);
+    // The log is shown as a block per repo, even with --stream
+    let output = execute::run_in_repo(
+        path,
+        &mut command,
+        options,
+        execute::Capture::Keep,
+        out,
+    )?;
+    write_to_buffer(&mut out.stdout, path, &output.stdout)?;
+
+    Ok(output.outcome)
+}
+
+//------------------------------------------------------------------------------
+pub fn run(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    options: &execute::Options,
+    id: &str,
+    log_args: &[String],
+) -> Result<()> {
+    let id = id.to_string();
+    let log_args = log_args.to_vec();
+
+    let task_options = options.clone();
+
+    execute::for_each_repo(regex, branch_regex, options, move |path, out| {
+        doit(&task_options, &id, &log_args, path, out)
+    })
+}
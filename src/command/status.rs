@@ -74,13 +74,13 @@ fn print_title(tracking: &Tracking) {
 //------------------------------------------------------------------------------
 fn status_thread(
     sender: &channel::StatusSender,
-    path: &path::PathBuf,
+    path: &path::Path,
     splitter: &regex::Regex,
     branch_filter: &BranchRegex,
 ) -> Result<()> {
     // Filter based on branch name
     if let Some(pattern) = branch_filter {
-        if !filter::branch(&pattern, path)? {
+        if !filter::branch(pattern, path)? {
             return Ok(());
         }
     }
@@ -89,8 +89,8 @@ fn status_thread(
 
     let args = ["status", "--porcelain"];
     let output = process::Command::new("git")
-        .args(&args)
-        .current_dir(path.clone())
+        .args(args)
+        .current_dir(path)
         .output()?;
 
     write_to_stderr(path, &output.stderr)?;
@@ -105,7 +105,7 @@ fn status_thread(
         if !split.is_empty() {
             let status = convert_to_status(&split[0][1])?;
             let file = &split[0][2];
-            file_path.push(path.clone());
+            file_path.push(path);
             file_path.push(file);
             sender.send((
                 branch_name.clone(),
@@ -241,7 +241,7 @@ pub fn run(regex: &regex::Regex, branch_regex: &BranchRegex) -> Result<()> {
             if !i.print_branch {
                 println!();
             }
-            print_title(&tracking);
+            print_title(tracking);
         }
 
         // Staging info
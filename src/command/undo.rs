@@ -0,0 +1,183 @@
+//------------------------------------------------------------------------------
+use crate::command::mv;
+use crate::error::Error;
+use crate::git;
+use crate::io::{argument_error, write_atomically};
+use crate::journal::{self, Entry, Journal};
+use crate::path;
+use crate::result::Result;
+//------------------------------------------------------------------------------
+use std::fs;
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Default)]
+pub struct Options {
+    pub force: bool,
+}
+
+//------------------------------------------------------------------------------
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => options.force = true,
+            _ => argument_error(&format!("unknown undo option '{0}'", arg)),
+        }
+    }
+    options
+}
+
+//------------------------------------------------------------------------------
+fn exists(path: &path::Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+//------------------------------------------------------------------------------
+// The paths that aren't as the operation left them. Files that were written
+// since only matter without --force, but a move can't be put back unless
+// its dest is still there and its source is free.
+fn changed_since(
+    journal: &Journal,
+    options: &Options,
+) -> Result<Vec<path::PathBuf>> {
+    let mut changed = Vec::new();
+    for entry in &journal.entries {
+        match entry {
+            Entry::Write { path, hash, .. } => {
+                let is_changed = match fs::read(path) {
+                    Ok(contents) => {
+                        !options.force && journal::hash(&contents) != *hash
+                    }
+                    Err(_) => true,
+                };
+                if is_changed {
+                    changed.push(path.clone());
+                }
+            }
+            Entry::Move(moved) => {
+                let from_path = moved.from_repo.join(&moved.from_rel);
+                let to_path = moved.to_repo.join(&moved.to_rel);
+                if !exists(&to_path) {
+                    changed.push(to_path);
+                } else if exists(&from_path) {
+                    changed.push(from_path);
+                } else if let Some((_, head)) = &moved.history {
+                    let current = git::current_head(&moved.to_repo)?;
+                    if current.as_ref() != Some(head) {
+                        changed.push(moved.to_repo.clone());
+                    }
+                }
+            }
+            // Put back once the move off it is undone
+            Entry::Overwrite { .. } => (),
+        }
+    }
+    Ok(changed)
+}
+
+//------------------------------------------------------------------------------
+// Put a move back
+fn undo_move(moved: &journal::Move) -> Result<()> {
+    let from_path = moved.from_repo.join(&moved.from_rel);
+    let to_path = moved.to_repo.join(&moved.to_rel);
+    let to_repo = moved.to_repo.as_path();
+    let to_rel = moved.to_rel.as_str();
+
+    // Like the move, only what git knew about is staged back at the source
+    let tracked_files = match moved.tracked {
+        true => git::tracked_files(to_repo, to_rel)?,
+        false => Vec::new(),
+    };
+
+    match &moved.history {
+        // Drop the replayed commits, leaving the working copy to move back
+        Some((Some(previous_head), _)) => {
+            git::run(to_repo, &["reset", "--quiet", previous_head])?;
+        }
+        // The replayed commits were the first in the repo
+        Some((None, _)) => {
+            git::run(to_repo, &["update-ref", "-d", "HEAD"])?;
+            git::run(
+                to_repo,
+                &["rm", "-r", "--cached", "--quiet", "--ignore-unmatch", "."],
+            )?;
+        }
+        None if moved.tracked => {
+            git::run(
+                to_repo,
+                &[
+                    "rm",
+                    "-r",
+                    "--cached",
+                    "--quiet",
+                    "--ignore-unmatch",
+                    to_rel,
+                ],
+            )?;
+        }
+        None => (),
+    }
+
+    fs::rename(&to_path, &from_path)?;
+    if moved.tracked {
+        let from_files: Vec<Vec<u8>> = tracked_files
+            .iter()
+            .map(|file| mv::rewrite_path(file, to_rel, &moved.from_rel))
+            .collect();
+        git::stage_files(&moved.from_repo, &from_files)?;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Revert the last replace or mv in every repo it touched, newest change
+// first
+pub fn run(options: &Options) -> Result<()> {
+    let journal = match Journal::load()? {
+        Some(journal) => journal,
+        None => {
+            println!("Nothing to undo.");
+            return Ok(());
+        }
+    };
+
+    // Check everything first, so the undo isn't left half done
+    let changed = changed_since(&journal, options)?;
+    if !changed.is_empty() {
+        return Err(Error::ChangedSince(changed));
+    }
+
+    for entry in journal.entries.iter().rev() {
+        match entry {
+            Entry::Write { path, backup, .. } => {
+                let contents = fs::read(journal.backup_path(backup))?;
+                write_atomically(path, &contents)?;
+            }
+            Entry::Move(moved) => undo_move(moved)?,
+            Entry::Overwrite {
+                repo,
+                rel,
+                backup,
+                tracked,
+            } => {
+                journal::copy_all(
+                    &journal.backup_path(backup),
+                    &repo.join(rel),
+                )?;
+                if *tracked {
+                    git::run(repo, &["add", rel])?;
+                }
+            }
+        }
+    }
+
+    println!(
+        "Undid {0}, {1} change(s)",
+        journal.operation,
+        journal.entries.len()
+    );
+    journal.remove()
+}
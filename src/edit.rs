@@ -0,0 +1,304 @@
+use super::patch;
+//------------------------------------------------------------------------------
+use std::ops::Range;
+
+//------------------------------------------------------------------------------
+// Edit
+//------------------------------------------------------------------------------
+// Replace a range of bytes in a file. Files are kept as bytes, so the
+// encoding and line endings come through untouched.
+#[derive(Clone)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub replacement: Vec<u8>,
+}
+
+//------------------------------------------------------------------------------
+// The text with the edits made, they have to be in order and not overlap
+pub fn apply(text: &[u8], edits: &[Edit]) -> Vec<u8> {
+    apply_within(text, 0..text.len(), edits)
+}
+
+//------------------------------------------------------------------------------
+// The edited text of just part of the file
+fn apply_within(text: &[u8], range: Range<usize>, edits: &[Edit]) -> Vec<u8> {
+    let mut edited = Vec::new();
+    let mut position = range.start;
+    for edit in edits {
+        edited.extend_from_slice(&text[position..edit.start]);
+        edited.extend_from_slice(&edit.replacement);
+        position = edit.end;
+    }
+    edited.extend_from_slice(&text[position..range.end]);
+    edited
+}
+
+//------------------------------------------------------------------------------
+// Where each line starts, and the end of the text
+fn line_starts(text: &[u8]) -> Vec<usize> {
+    let mut starts = vec![0];
+    for (i, byte) in text.iter().enumerate() {
+        if *byte == b'\n' && i + 1 < text.len() {
+            starts.push(i + 1);
+        }
+    }
+    starts.push(text.len());
+    starts
+}
+
+//------------------------------------------------------------------------------
+fn line_of(starts: &[usize], offset: usize) -> usize {
+    match starts.binary_search(&offset) {
+        Ok(line) => line.min(starts.len() - 2),
+        Err(line) => line - 1,
+    }
+}
+
+//------------------------------------------------------------------------------
+// A line to show in a diff, without its line ending
+fn display_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).to_string()
+}
+
+//------------------------------------------------------------------------------
+fn split_lines(text: &[u8]) -> Vec<String> {
+    text.split_inclusive(|byte| *byte == b'\n')
+        .map(display_line)
+        .collect()
+}
+
+//------------------------------------------------------------------------------
+// The first and last lines an edit touches
+fn line_span(starts: &[usize], edit: &Edit) -> (usize, usize) {
+    let first = line_of(starts, edit.start);
+    let last = line_of(starts, edit.end.max(edit.start + 1) - 1);
+    (first, last.max(first))
+}
+
+//------------------------------------------------------------------------------
+// The edits as the hunks of a unified diff, each with this many lines of
+// context. Edits close enough to share their context go in the same hunk.
+pub fn hunks(text: &[u8], edits: &[Edit], context: usize) -> Vec<Vec<String>> {
+    if edits.is_empty() {
+        return Vec::new();
+    }
+
+    let starts = line_starts(text);
+    let line_count = starts.len() - 1;
+    let old_lines: Vec<String> = (0..line_count)
+        .map(|line| display_line(&text[starts[line]..starts[line + 1]]))
+        .collect();
+
+    // Group the edits into the hunks, and the hunks into the runs of edits
+    // on the same or neighbouring lines. They're in order, so a new group or run always
+    // starts after the last line seen.
+    let spans: Vec<(usize, usize)> =
+        edits.iter().map(|edit| line_span(&starts, edit)).collect();
+    let mut groups: Vec<Vec<Vec<usize>>> = Vec::new();
+    let mut group_last = 0;
+    let mut run_last = 0;
+    for (i, (first, last)) in spans.iter().enumerate() {
+        match groups.last_mut() {
+            Some(group) if *first <= group_last + 2 * context + 1 => {
+                match group.last_mut() {
+                    Some(run) if *first <= run_last + 1 => run.push(i),
+                    _ => group.push(vec![i]),
+                }
+            }
+            _ => groups.push(vec![vec![i]]),
+        }
+        group_last = group_last.max(*last);
+        run_last = run_last.max(*last);
+    }
+
+    let mut hunks = Vec::new();
+    let mut delta: isize = 0;
+    for group in groups {
+        let group_first = spans[group[0][0]].0;
+        let group_end = group
+            .iter()
+            .flatten()
+            .map(|i| spans[*i].1)
+            .max()
+            .unwrap_or(0);
+        let old_from = group_first.saturating_sub(context);
+        let old_to = (group_end + context).min(line_count - 1);
+
+        let mut lines = Vec::new();
+        let mut new_count = 0;
+        let mut line = old_from;
+        for run in &group {
+            let run_edits: Vec<Edit> =
+                run.iter().map(|i| edits[*i].clone()).collect();
+            let first = spans[run[0]].0;
+            let last = run.iter().map(|i| spans[*i].1).max().unwrap_or(first);
+
+            while line < first {
+                lines.push(format!(" {0}", old_lines[line]));
+                new_count += 1;
+                line += 1;
+            }
+            for old_line in &old_lines[first..=last] {
+                lines.push(format!("-{0}", old_line));
+            }
+            let range = starts[first]..starts[last + 1];
+            for new_line in split_lines(&apply_within(text, range, &run_edits))
+            {
+                lines.push(format!("+{0}", new_line));
+                new_count += 1;
+            }
+            line = last + 1;
+        }
+        while line <= old_to {
+            lines.push(format!(" {0}", old_lines[line]));
+            new_count += 1;
+            line += 1;
+        }
+
+        let old_count = old_to + 1 - old_from;
+        let new_from = old_from as isize + delta;
+        delta += new_count as isize - old_count as isize;
+
+        let mut hunk = vec![format!(
+            "@@ -{0},{1} +{2},{3} @@",
+            old_from + 1,
+            old_count,
+            new_from + 1,
+            new_count
+        )];
+        hunk.append(&mut lines);
+        hunks.push(hunk);
+    }
+
+    hunks
+}
+
+//------------------------------------------------------------------------------
+// A diff of the edits for someone to read, with the path as the header
+pub fn write_diff(
+    handle: &mut dyn std::io::Write,
+    display_path: &str,
+    text: &[u8],
+    edits: &[Edit],
+) -> std::io::Result<()> {
+    patch::write_header(handle, display_path)?;
+    for hunk in hunks(text, edits, 3) {
+        patch::write_hunk(handle, &hunk)?;
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    fn numbered_lines(count: usize) -> Vec<u8> {
+        (1..=count)
+            .map(|line| format!("l{0}\n", line))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    //--------------------------------------------------------------------------
+    // Replace the first occurrence of the needle
+    fn edit_of(text: &[u8], needle: &str, replacement: &str) -> Edit {
+        let start = text
+            .windows(needle.len())
+            .position(|window| window == needle.as_bytes())
+            .unwrap();
+        Edit {
+            start,
+            end: start + needle.len(),
+            replacement: replacement.as_bytes().to_vec(),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn no_edits_no_hunks() {
+        assert!(hunks(b"a\nb\n", &[], 3).is_empty());
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn one_edit_with_context() {
+        let text = b"a\nb\nc\nd\ne\nf\ng\nh\ni\n";
+        let edits = [edit_of(text, "e", "E")];
+        assert_eq!(
+            hunks(text, &edits, 3),
+            vec![vec![
+                "@@ -2,7 +2,7 @@",
+                " b",
+                " c",
+                " d",
+                "-e",
+                "+E",
+                " f",
+                " g",
+                " h",
+            ]]
+        );
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn close_edits_share_a_hunk() {
+        let text = numbered_lines(20);
+        let edits = [edit_of(&text, "l1", "L1"), edit_of(&text, "l5", "L5")];
+        assert_eq!(
+            hunks(&text, &edits, 3),
+            vec![vec![
+                "@@ -1,8 +1,8 @@",
+                "-l1",
+                "+L1",
+                " l2",
+                " l3",
+                " l4",
+                "-l5",
+                "+L5",
+                " l6",
+                " l7",
+                " l8",
+            ]]
+        );
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn later_hunks_allow_for_added_lines() {
+        let text = numbered_lines(20);
+        let edits =
+            [edit_of(&text, "l1", "x\ny"), edit_of(&text, "l20", "L20")];
+        assert_eq!(
+            hunks(&text, &edits, 3),
+            vec![
+                vec!["@@ -1,4 +1,5 @@", "-l1", "+x", "+y", " l2", " l3", " l4"],
+                vec![
+                    "@@ -17,4 +18,4 @@",
+                    " l17",
+                    " l18",
+                    " l19",
+                    "-l20",
+                    "+L20",
+                ],
+            ]
+        );
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn line_endings_are_not_shown() {
+        let text = b"a\r\nb\r\n";
+        let edits = [edit_of(text, "b", "B")];
+        assert_eq!(
+            hunks(text, &edits, 3),
+            vec![vec!["@@ -1,2 +1,2 @@", " a", "-b", "+B"]]
+        );
+        assert_eq!(apply(text, &edits), b"a\r\nB\r\n");
+    }
+}
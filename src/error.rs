@@ -31,12 +31,143 @@ pub enum Error {
     RelativeToRepo(),
     Infallible(std::convert::Infallible),
     UnableToParseStatus,
+    Unsuccessful(usize),
+    DependencyCycle(Vec<path::PathBuf>),
+    NoEditor,
+    EmptyCommitMessage,
+    UnableToDiff,
+    Lock,
+    UnableToRollBack(path::PathBuf),
+    PathspecDidNotMatch(String),
+    NotInARepo(path::PathBuf),
+    NoHistory(path::PathBuf),
+    DirtyIndex(path::PathBuf),
+    UnableToReplayHistory(path::PathBuf),
+    BadSource(path::PathBuf),
+    NotADirectory(path::PathBuf),
+    MoveIntoItself(path::PathBuf),
+    MoveRepo(path::PathBuf),
+    DestinationExists(path::PathBuf),
+    MultipleSources(path::PathBuf),
+    BadJournal,
+    ChangedSince(Vec<path::PathBuf>),
+    UnableToStage(path::PathBuf),
 }
 
 //------------------------------------------------------------------------------
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error")
+        match self {
+            Error::IO(error) => write!(f, "{0}", error),
+            Error::PathSend(error) => write!(f, "{0}", error),
+            Error::StatusSend(error) => write!(f, "{0}", error),
+            Error::Recv(error) => write!(f, "{0}", error),
+            Error::Regex(error) => write!(f, "{0}", error),
+            Error::Thread(error) => match error.downcast_ref::<&str>() {
+                Some(msg) => write!(f, "A worker thread panicked: {0}", msg),
+                None => write!(f, "A worker thread panicked"),
+            },
+            Error::StripPrefix(error) => write!(f, "{0}", error),
+            Error::RelativeToRepo() => {
+                write!(f, "The path is not inside a git repo")
+            }
+            Error::UnableToParseStatus => {
+                write!(f, "Unable to parse the output of git status")
+            }
+            Error::Unsuccessful(count) => {
+                write!(f, "{0} repo(s) did not succeed", count)
+            }
+            Error::DependencyCycle(repos) => {
+                write!(f, "The repos depend on each other in a cycle:")?;
+                for repo in repos {
+                    write!(f, " {0} ->", repo.display())?;
+                }
+                match repos.first() {
+                    Some(repo) => write!(f, " {0}", repo.display()),
+                    None => Ok(()),
+                }
+            }
+            Error::NoEditor => {
+                write!(f, "Unable to open an editor for the commit message")
+            }
+            Error::EmptyCommitMessage => {
+                write!(f, "Aborting commit due to empty commit message")
+            }
+            Error::UnableToDiff => {
+                write!(f, "Unable to compare the index with HEAD")
+            }
+            Error::Lock => write!(f, "A lock was poisoned by a panic"),
+            Error::UnableToRollBack(repo) => {
+                write!(
+                    f,
+                    "Unable to roll back the commit in {0}",
+                    repo.display()
+                )
+            }
+            Error::PathspecDidNotMatch(pathspec) => {
+                write!(f, "pathspec '{0}' did not match any files", pathspec)
+            }
+            Error::NotInARepo(path) => write!(
+                f,
+                "'{0}' is not in a repo and there are no repos below it",
+                path.display()
+            ),
+            Error::NoHistory(path) => write!(
+                f,
+                "'{0}' has no commits to move with it",
+                path.display()
+            ),
+            Error::DirtyIndex(repo) => write!(
+                f,
+                "{0} has staged changes, commit or reset them first",
+                repo.display()
+            ),
+            Error::UnableToReplayHistory(repo) => write!(
+                f,
+                "Unable to replay the history in {0}, nothing was moved",
+                repo.display()
+            ),
+            Error::BadSource(path) => write!(
+                f,
+                "bad source '{0}', no such file or directory",
+                path.display()
+            ),
+            Error::NotADirectory(path) => {
+                write!(f, "destination '{0}' is not a directory", path.display())
+            }
+            Error::MoveIntoItself(path) => {
+                write!(f, "can not move '{0}' into itself", path.display())
+            }
+            Error::MoveRepo(path) => write!(
+                f,
+                "'{0}' is a repo, only files and directories in it can be moved",
+                path.display()
+            ),
+            Error::DestinationExists(path) => write!(
+                f,
+                "destination '{0}' exists, use -f to overwrite it",
+                path.display()
+            ),
+            Error::MultipleSources(path) => write!(
+                f,
+                "multiple sources for the same target '{0}'",
+                path.display()
+            ),
+            Error::BadJournal => write!(f, "Unable to read the undo journal"),
+            Error::ChangedSince(paths) => {
+                write!(f, "These have changed since, so they can't be undone:")?;
+                for path in paths {
+                    write!(f, "\n    {0}", path.display())?;
+                }
+                Ok(())
+            }
+            Error::UnableToStage(repo) => write!(
+                f,
+                "Unable to stage the chosen hunks in {0}",
+                repo.display()
+            ),
+            _ => write!(f, "Error"),
+        }
     }
 }
 
@@ -95,3 +226,10 @@ impl From<std::convert::Infallible> for Error {
         Error::Infallible(error)
     }
 }
+
+//------------------------------------------------------------------------------
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        Error::Lock
+    }
+}
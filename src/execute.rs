@@ -0,0 +1,740 @@
+use super::branch_regex::BranchRegex;
+use super::error::Error;
+use super::filter;
+use super::io;
+use super::path;
+use super::repoiterator::RepoIterator;
+use super::result::{get, handle_errors, Result};
+use super::topo;
+//------------------------------------------------------------------------------
+use colored::*;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//------------------------------------------------------------------------------
+// How often a running child is checked for exit, timeout or interruption
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+//------------------------------------------------------------------------------
+// The number of times SIGINT has been received
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+//------------------------------------------------------------------------------
+// Set when a repo has failed and --fail-fast was given
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+//------------------------------------------------------------------------------
+// Options
+//------------------------------------------------------------------------------
+#[derive(Clone)]
+pub struct Options {
+    // Print each line of a child process as it arrives
+    pub stream: bool,
+    // Hold back the output and print it sorted by repo path
+    pub ordered: bool,
+    // Kill the child processes of a repo that take longer than this
+    pub timeout: Option<Duration>,
+    // How many more times to run a command that failed or timed out
+    pub retries: usize,
+    // How long to wait before each retry
+    pub retry_delay: Duration,
+    // Stop everything as soon as one repo doesn't succeed
+    pub fail_fast: bool,
+    // Run the repos in the order of their dependencies
+    pub topo: bool,
+}
+
+//------------------------------------------------------------------------------
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            stream: false,
+            ordered: false,
+            timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            fail_fast: false,
+            topo: false,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+impl Options {
+    // Retrying is only for what goes over the network, everything else
+    // fails the same way the second time
+    pub fn without_retries(&self) -> Self {
+        Options {
+            retries: 0,
+            ..self.clone()
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Capture
+//------------------------------------------------------------------------------
+// What happens to the stdout of a command run in a repo
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    // Passed on to the user, as it arrives with --stream
+    Print,
+    // Handed back to be looked at, so it's never streamed
+    Keep,
+}
+
+//------------------------------------------------------------------------------
+// Outcome
+//------------------------------------------------------------------------------
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failed(Option<i32>),
+    Error,
+    TimedOut,
+    Interrupted,
+    Cancelled,
+    Blocked,
+}
+
+//------------------------------------------------------------------------------
+impl Outcome {
+    fn describe(&self) -> String {
+        match self {
+            Outcome::Success => "ok".to_string(),
+            Outcome::Failed(Some(code)) => {
+                format!("failed (exit code {0})", code)
+            }
+            Outcome::Failed(None) => "failed".to_string(),
+            Outcome::Error => "error".to_string(),
+            Outcome::TimedOut => "timed out".to_string(),
+            Outcome::Interrupted => "interrupted".to_string(),
+            Outcome::Cancelled => "cancelled".to_string(),
+            Outcome::Blocked => {
+                "not run, a dependency did not succeed".to_string()
+            }
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Output
+//------------------------------------------------------------------------------
+// What a single repo wants printed, held back until the repo is done.
+#[derive(Default)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+//------------------------------------------------------------------------------
+impl Output {
+    fn flush(&self) -> Result<()> {
+        if !self.stdout.is_empty() {
+            std::io::stdout().lock().write_all(&self.stdout)?;
+        }
+        if !self.stderr.is_empty() {
+            std::io::stderr().lock().write_all(&self.stderr)?;
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+// Finished
+//------------------------------------------------------------------------------
+// A child process that has exited. When streaming, stdout and stderr have
+// already been printed and are left empty.
+pub struct Finished {
+    pub outcome: Outcome,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+//------------------------------------------------------------------------------
+// Interruption
+//------------------------------------------------------------------------------
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+//------------------------------------------------------------------------------
+// Catch Ctrl-C while children are running, so instead of dying and leaving
+// them running we can pass it on to them and wait for them to exit. Ctrl-C
+// goes back to killing the process once it's dropped, so a prompt or
+// anything else that doesn't check for it can still be interrupted.
+struct InterruptHandler;
+
+//------------------------------------------------------------------------------
+impl InterruptHandler {
+    #[cfg(unix)]
+    fn install() -> Self {
+        let handler = on_interrupt as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+        InterruptHandler
+    }
+
+    #[cfg(not(unix))]
+    fn install() -> Self {
+        InterruptHandler
+    }
+}
+
+//------------------------------------------------------------------------------
+impl Drop for InterruptHandler {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+//------------------------------------------------------------------------------
+pub fn interrupted() -> bool {
+    INTERRUPTS.load(Ordering::SeqCst) != 0
+}
+
+//------------------------------------------------------------------------------
+fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+//------------------------------------------------------------------------------
+#[cfg(unix)]
+fn own_process_group(command: &mut process::Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+//------------------------------------------------------------------------------
+#[cfg(not(unix))]
+fn own_process_group(_: &mut process::Command) {}
+
+//------------------------------------------------------------------------------
+// Send a signal to the child, and to everything it started when it's the
+// leader of its own process group.
+#[cfg(unix)]
+fn signal(child: &process::Child, group: bool, signal: libc::c_int) {
+    let pid = child.id() as libc::pid_t;
+    unsafe {
+        if group {
+            libc::killpg(pid, signal);
+        } else {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+#[cfg(unix)]
+fn interrupt(child: &mut process::Child, group: bool) {
+    signal(child, group, libc::SIGINT);
+}
+
+//------------------------------------------------------------------------------
+#[cfg(unix)]
+fn kill(child: &mut process::Child, group: bool) {
+    signal(child, group, libc::SIGKILL);
+}
+
+//------------------------------------------------------------------------------
+#[cfg(not(unix))]
+fn interrupt(child: &mut process::Child, _: bool) {
+    let _ = child.kill();
+}
+
+//------------------------------------------------------------------------------
+#[cfg(not(unix))]
+fn kill(child: &mut process::Child, _: bool) {
+    let _ = child.kill();
+}
+
+//------------------------------------------------------------------------------
+fn read_output(
+    repo: &path::Path,
+    input: impl Read + Send + 'static,
+    stream: bool,
+    to_stderr: bool,
+) -> thread::JoinHandle<Result<Vec<u8>>> {
+    let repo = repo.to_path_buf();
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if stream {
+            io::stream_lines(&repo, input, to_stderr)?;
+        } else {
+            let mut input = input;
+            input.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    })
+}
+
+//------------------------------------------------------------------------------
+fn run_once(
+    repo: &path::Path,
+    command: &mut process::Command,
+    options: &Options,
+) -> Result<Finished> {
+    // A child in its own process group can be killed along with everything
+    // it started. It also loses the terminal, so only do it when asked to.
+    let group = options.timeout.is_some();
+    if group {
+        own_process_group(command);
+    }
+
+    let mut child = command
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+
+    // Both pipes are drained on their own threads so a child blocked on a
+    // full pipe can't stall.
+    let stdout =
+        read_output(repo, get(child.stdout.take())?, options.stream, false);
+    let stderr =
+        read_output(repo, get(child.stderr.take())?, options.stream, true);
+
+    let start = Instant::now();
+    let mut interrupts = 0;
+    let mut cancelling = false;
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        // Pass Ctrl-C on, and give up waiting politely the second time. A
+        // child in the terminal's process group has already had it from the
+        // terminal.
+        let received = INTERRUPTS.load(Ordering::SeqCst);
+        if received != interrupts {
+            if received == 1 {
+                if group {
+                    interrupt(&mut child, group);
+                }
+            } else {
+                kill(&mut child, group);
+            }
+            interrupts = received;
+        }
+
+        // Another repo failed, so stop this one the way Ctrl-C would
+        if !cancelling && cancelled() {
+            interrupt(&mut child, group);
+            cancelling = true;
+        }
+
+        if let Some(timeout) = options.timeout {
+            if !timed_out && start.elapsed() >= timeout {
+                kill(&mut child, group);
+                timed_out = true;
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let outcome = if timed_out {
+        Outcome::TimedOut
+    } else if interrupts != 0 || (interrupted() && !status.success()) {
+        // A child that had Ctrl-C from the terminal may have exited before
+        // we noticed it
+        Outcome::Interrupted
+    } else if cancelling {
+        Outcome::Cancelled
+    } else if status.success() {
+        Outcome::Success
+    } else {
+        Outcome::Failed(status.code())
+    };
+
+    Ok(Finished {
+        outcome,
+        stdout: stdout.join()??,
+        stderr: stderr.join()??,
+    })
+}
+
+//------------------------------------------------------------------------------
+// Sleep, waking up early if there's no longer any point in waiting
+fn wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration && !interrupted() && !cancelled() {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+//------------------------------------------------------------------------------
+// Run a child process in the given repo, honouring the timeout and retries
+// and passing on Ctrl-C.
+pub fn run_command(
+    repo: &path::Path,
+    command: &mut process::Command,
+    options: &Options,
+) -> Result<Finished> {
+    run_prepared_command(repo, command, options, || Ok(()))
+}
+
+//------------------------------------------------------------------------------
+// Run a command in the repo as a task does. Its stderr always goes to the
+// repo's output, and its stdout does too unless it's kept to be looked at,
+// in which case it's in what's returned.
+pub fn run_in_repo(
+    repo: &path::Path,
+    command: &mut process::Command,
+    options: &Options,
+    capture: Capture,
+    out: &mut Output,
+) -> Result<Finished> {
+    let options = match capture {
+        Capture::Print => options.clone(),
+        Capture::Keep => Options {
+            stream: false,
+            ..options.clone()
+        },
+    };
+    let mut finished = run_command(repo, command, &options)?;
+
+    // These are empty if they were streamed
+    if capture == Capture::Print {
+        io::write_to_buffer(&mut out.stdout, repo, &finished.stdout)?;
+        finished.stdout.clear();
+    }
+    io::write_to_buffer(&mut out.stderr, repo, &finished.stderr)?;
+    finished.stderr.clear();
+
+    Ok(finished)
+}
+
+//------------------------------------------------------------------------------
+// As run_command, calling prepare before every attempt, so anything an
+// attempt that failed or was killed left behind can be cleared away.
+pub fn run_prepared_command<P>(
+    repo: &path::Path,
+    command: &mut process::Command,
+    options: &Options,
+    prepare: P,
+) -> Result<Finished>
+where
+    P: Fn() -> Result<()>,
+{
+    let mut earlier_stderr = Vec::new();
+    let mut attempt = 0;
+    loop {
+        prepare()?;
+        let mut finished = run_once(repo, command, options)?;
+
+        let transient =
+            matches!(finished.outcome, Outcome::Failed(_) | Outcome::TimedOut);
+        let stopping = interrupted() || cancelled();
+        if !transient || stopping || attempt == options.retries {
+            // Keep what went wrong in the earlier attempts
+            earlier_stderr.append(&mut finished.stderr);
+            finished.stderr = earlier_stderr;
+            return Ok(finished);
+        }
+
+        attempt += 1;
+        let note = format!(
+            "{0}, retrying in {1:?} ({2} of {3})",
+            finished.outcome.describe(),
+            options.retry_delay,
+            attempt,
+            options.retries
+        );
+        if options.stream {
+            io::write_prefixed_line(
+                &mut std::io::stderr().lock(),
+                repo,
+                note.as_bytes(),
+            )?;
+        } else {
+            earlier_stderr.append(&mut finished.stderr);
+            writeln!(earlier_stderr, "{0}", note.yellow())?;
+        }
+
+        wait(options.retry_delay);
+    }
+}
+
+//------------------------------------------------------------------------------
+fn run_task<F>(
+    task: &F,
+    branch_filter: &BranchRegex,
+    path: &path::Path,
+    output: &mut Output,
+) -> Result<Outcome>
+where
+    F: Fn(&path::Path, &mut Output) -> Result<Outcome>,
+{
+    // Filter based on branch name
+    if let Some(pattern) = branch_filter {
+        if !filter::branch(pattern, path)? {
+            return Ok(Outcome::Success);
+        }
+    }
+
+    task(path, output)
+}
+
+//------------------------------------------------------------------------------
+fn print_summary(results: &[(path::PathBuf, Output, Outcome)]) -> Result<()> {
+    let stderr = std::io::stderr();
+    let mut handle = stderr.lock();
+
+    let mut first = true;
+    for (path, _, outcome) in results {
+        if *outcome != Outcome::Success {
+            if first {
+                writeln!(handle)?;
+                first = false;
+            }
+            writeln!(
+                handle,
+                "{0}: {1}",
+                get(path.to_str())?.cyan(),
+                outcome.describe().red()
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+type Results = Vec<(path::PathBuf, Output, Outcome)>;
+
+//------------------------------------------------------------------------------
+fn run_all<I, F>(
+    paths: I,
+    branch_regex: &BranchRegex,
+    options: &Options,
+    task: &Arc<F>,
+) -> Result<Results>
+where
+    I: IntoIterator<Item = path::PathBuf>,
+    F: Fn(&path::Path, &mut Output) -> Result<Outcome> + Send + Sync + 'static,
+{
+    let ordered = options.ordered;
+    let fail_fast = options.fail_fast;
+    let mut threads = Vec::new();
+
+    for path in paths {
+        // Don't start anything new once we've been interrupted
+        if interrupted() {
+            break;
+        }
+
+        let task = task.clone();
+        let branch_filter = branch_regex.clone();
+
+        // Execute a new thread for processing this result
+        threads.push(thread::spawn(move || {
+            let mut output = Output::default();
+            let outcome = if cancelled() {
+                Outcome::Cancelled
+            } else {
+                match run_task(&*task, &branch_filter, &path, &mut output) {
+                    Ok(outcome) => outcome,
+                    Err(error) => {
+                        handle_errors::<()>(Err(error));
+                        Outcome::Error
+                    }
+                }
+            };
+
+            // Stop the others
+            let failed =
+                !matches!(outcome, Outcome::Success | Outcome::Cancelled);
+            if fail_fast && failed {
+                CANCELLED.store(true, Ordering::SeqCst);
+            }
+
+            if !ordered {
+                handle_errors(output.flush());
+            }
+            (path, output, outcome)
+        }));
+    }
+
+    // Wait for all the threads to finish
+    let mut results = Vec::new();
+    for thread in threads {
+        results.push(thread.join()?);
+    }
+
+    Ok(results)
+}
+
+//------------------------------------------------------------------------------
+// Run the repos a wave at a time, so a repo only starts once everything it
+// depends on has succeeded.
+fn run_topo<I, F>(
+    paths: I,
+    branch_regex: &BranchRegex,
+    options: &Options,
+    task: &Arc<F>,
+) -> Result<Results>
+where
+    I: IntoIterator<Item = path::PathBuf>,
+    F: Fn(&path::Path, &mut Output) -> Result<Outcome> + Send + Sync + 'static,
+{
+    let waves = topo::waves(paths.into_iter().collect())?;
+
+    let mut results = Vec::new();
+    let mut unsuccessful = HashSet::new();
+    for wave in waves {
+        if interrupted() {
+            break;
+        }
+
+        let mut ready = Vec::new();
+        for node in wave {
+            if node.depends_on.iter().any(|d| unsuccessful.contains(d)) {
+                unsuccessful.insert(node.path.clone());
+                results.push((node.path, Output::default(), Outcome::Blocked));
+            } else {
+                ready.push(node.path);
+            }
+        }
+
+        for result in run_all(ready, branch_regex, options, task)? {
+            if result.2 != Outcome::Success {
+                unsuccessful.insert(result.0.clone());
+            }
+            results.push(result);
+        }
+    }
+
+    Ok(results)
+}
+
+//------------------------------------------------------------------------------
+// Run the task on its own thread for every path given.
+//
+// The task writes into an Output rather than straight to stdout/stderr.
+// Normally each repo's output is printed as soon as that repo finishes,
+// when ordered it is held back and printed sorted by repo path. Any repo
+// that didn't succeed is listed at the end.
+pub fn for_each<I, F>(
+    paths: I,
+    branch_regex: &BranchRegex,
+    options: &Options,
+    task: F,
+) -> Result<()>
+where
+    I: IntoIterator<Item = path::PathBuf>,
+    F: Fn(&path::Path, &mut Output) -> Result<Outcome> + Send + Sync + 'static,
+{
+    let task = Arc::new(task);
+    let interrupt_handler = InterruptHandler::install();
+    let mut results = if options.topo {
+        run_topo(paths, branch_regex, options, &task)?
+    } else {
+        run_all(paths, branch_regex, options, &task)?
+    };
+    drop(interrupt_handler);
+
+    // Print everything in a stable order
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    if options.ordered {
+        for (_, output, _) in &results {
+            output.flush()?;
+        }
+    }
+
+    print_summary(&results)?;
+
+    let unsuccessful = results
+        .iter()
+        .filter(|(_, _, outcome)| *outcome != Outcome::Success)
+        .count();
+    if unsuccessful != 0 {
+        return Err(Error::Unsuccessful(unsuccessful));
+    }
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Run the task on its own thread for every repo matching the filters.
+pub fn for_each_repo<F>(
+    path_regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    options: &Options,
+    task: F,
+) -> Result<()>
+where
+    F: Fn(&path::Path, &mut Output) -> Result<Outcome> + Send + Sync + 'static,
+{
+    // Loop through the results of what the walker is outputting
+    for_each(RepoIterator::new(path_regex), branch_regex, options, task)
+}
+
+//------------------------------------------------------------------------------
+// Parse a duration such as '90', '90s', '500ms', '5m' or '1h'. A plain
+// number is in seconds.
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let split = input
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let value: f64 = number.parse().ok()?;
+
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 60.0 * 60.0,
+        _ => return None,
+    };
+
+    // Too long to be a Duration
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn plain_numbers_are_seconds() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("0.5"), Some(Duration::from_millis(500)));
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn units() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn bad_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("."), None);
+        assert_eq!(parse_duration("5d"), None);
+        assert_eq!(parse_duration("-5"), None);
+        assert_eq!(parse_duration("99999999999999999999h"), None);
+    }
+}
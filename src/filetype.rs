@@ -0,0 +1,135 @@
+use super::execute;
+use super::io::{argument_error, write_to_buffer};
+use super::path;
+use super::result::Result;
+//------------------------------------------------------------------------------
+use std::process;
+
+//------------------------------------------------------------------------------
+// The file names of each type, either '*.<extension>' or a whole name
+const TYPES: &[(&str, &[&str])] = &[
+    ("c", &["*.c", "*.h"]),
+    ("cmake", &["*.cmake", "CMakeLists.txt"]),
+    (
+        "cpp",
+        &[
+            "*.c", "*.cc", "*.cpp", "*.cxx", "*.c++", "*.h", "*.hh", "*.hpp",
+            "*.hxx", "*.h++", "*.inl", "*.ipp",
+        ],
+    ),
+    ("go", &["*.go"]),
+    ("java", &["*.java"]),
+    ("js", &["*.js", "*.jsx", "*.mjs", "*.cjs"]),
+    ("json", &["*.json"]),
+    ("markdown", &["*.md", "*.markdown"]),
+    ("python", &["*.py", "*.pyi"]),
+    ("rust", &["*.rs"]),
+    ("sh", &["*.sh", "*.bash", "*.zsh"]),
+    ("toml", &["*.toml"]),
+    ("ts", &["*.ts", "*.tsx"]),
+    ("yaml", &["*.yaml", "*.yml"]),
+];
+
+//------------------------------------------------------------------------------
+// The file names of the type, exiting with the known types when it's not one
+pub fn patterns(name: &str) -> &'static [&'static str] {
+    match TYPES.iter().find(|(type_name, _)| *type_name == name) {
+        Some((_, patterns)) => patterns,
+        None => {
+            let names: Vec<&str> =
+                TYPES.iter().map(|(name, _)| *name).collect();
+            argument_error(&format!(
+                "unknown type '{0}', the types are {1}",
+                name,
+                names.join(", ")
+            ));
+            &[]
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+fn matches(pattern: &str, file: &str) -> bool {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    match pattern.strip_prefix('*') {
+        Some(extension) => name.ends_with(extension),
+        None => name == pattern,
+    }
+}
+
+//------------------------------------------------------------------------------
+// Selection
+//------------------------------------------------------------------------------
+// The files to look at in each repo, picked by pathspecs relative to the
+// root of the repo and by type. A file has to match both.
+#[derive(Clone, Default)]
+pub struct Selection {
+    pub pathspecs: Vec<String>,
+    pub types: Vec<&'static str>,
+}
+
+//------------------------------------------------------------------------------
+impl Selection {
+    pub fn add_type(&mut self, name: &str) {
+        self.types.extend_from_slice(patterns(name));
+    }
+
+    fn matches_type(&self, file: &str) -> bool {
+        self.types.is_empty()
+            || self.types.iter().any(|pattern| matches(pattern, file))
+    }
+
+    // The files git tracks in the repo that are selected, or None when git
+    // couldn't list them
+    pub fn list_files(
+        &self,
+        repo: &path::Path,
+        out: &mut execute::Output,
+    ) -> Result<Option<Vec<String>>> {
+        let output = process::Command::new("git")
+            .args(["ls-files", "-z", "--"])
+            .args(&self.pathspecs)
+            .current_dir(repo)
+            .output()?;
+
+        write_to_buffer(&mut out.stderr, repo, &output.stderr)?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let files = output
+            .stdout
+            .split(|byte| *byte == 0)
+            .filter(|file| !file.is_empty())
+            .map(|file| String::from_utf8_lossy(file).to_string())
+            .filter(|file| self.matches_type(file))
+            .collect();
+        Ok(Some(files))
+    }
+
+    // The pathspecs to give git for the selection. Git ORs pathspecs
+    // together, so with both pathspecs and types only the pathspecs are
+    // given, and what git finds has to be checked against list_files.
+    pub fn git_pathspecs(&self) -> Vec<String> {
+        if self.pathspecs.is_empty() {
+            return self.types.iter().map(|t| to_pathspec(t)).collect();
+        }
+        self.pathspecs.clone()
+    }
+
+    // Whether git_pathspecs leaves out the types
+    pub fn has_pathspecs_and_types(&self) -> bool {
+        !self.pathspecs.is_empty() && !self.types.is_empty()
+    }
+}
+
+//------------------------------------------------------------------------------
+// A wildcard in a pathspec matches '/' too, so only whole names need telling
+// to look in every directory
+fn to_pathspec(pattern: &str) -> String {
+    if pattern.starts_with('*') {
+        pattern.to_string()
+    } else {
+        format!(":(glob)**/{0}", pattern)
+    }
+}
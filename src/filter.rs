@@ -5,7 +5,7 @@ use super::result;
 //------------------------------------------------------------------------------
 pub fn branch(
     expression: &regex::Regex,
-    path: &path::PathBuf,
+    path: &path::Path,
 ) -> result::Result<bool> {
     let branch_name = git::get_branch_name(path)?;
 
@@ -5,16 +5,17 @@ use super::result;
 //------------------------------------------------------------------------------
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Write;
 use std::process;
 
 //------------------------------------------------------------------------------
-pub fn get_branch_name(path: &path::PathBuf) -> result::Result<String> {
+pub fn get_branch_name(path: &path::Path) -> result::Result<String> {
     let output = process::Command::new("git")
-        .args(&["rev-parse", "--abbrev-ref", "HEAD"])
-        .current_dir(path.clone())
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .current_dir(path)
         .output()?;
 
-    io::write_to_stderr(&path, &output.stderr)?;
+    io::write_to_stderr(path, &output.stderr)?;
 
     let stdout = BufReader::new(&output.stdout as &[u8]);
     let result: Vec<_> = stdout.lines().collect();
@@ -31,8 +32,13 @@ pub fn get_branch_name(path: &path::PathBuf) -> result::Result<String> {
 
 //------------------------------------------------------------------------------
 pub fn relative_to_repo(
-    path: &mut path::PathBuf,
+    path: &path::Path,
 ) -> result::Result<(path::PathBuf, String)> {
+    // The root of a repo
+    if path.join(".git").exists() {
+        return Ok((path.to_path_buf(), ".".to_string()));
+    }
+
     for parent in path.ancestors() {
         if !parent.as_os_str().is_empty() {
             let mut repo = path::PathBuf::new(); // TODO LT: Wanted to keep this around but need nightly to use 'clear'.
@@ -42,9 +48,8 @@ pub fn relative_to_repo(
 
             if repo.exists() {
                 repo.pop();
-                let relative_path = result::get(
-                    path.as_path().strip_prefix(repo.as_path())?.to_str(),
-                )?;
+                let relative_path =
+                    result::get(path.strip_prefix(repo.as_path())?.to_str())?;
                 return Ok((repo, relative_path.to_string()));
             }
         }
@@ -52,3 +57,107 @@ pub fn relative_to_repo(
 
     Err(error::Error::RelativeToRepo())
 }
+
+//------------------------------------------------------------------------------
+// Run git in the repo, passing on what it writes to stderr, and whether it
+// succeeded
+pub fn run(repo: &path::Path, args: &[&str]) -> result::Result<bool> {
+    let output = process::Command::new("git")
+        .args(args)
+        .current_dir(repo)
+        .output()?;
+
+    io::write_to_stderr(repo, &output.stderr)?;
+
+    Ok(output.status.success())
+}
+
+//------------------------------------------------------------------------------
+// The commit HEAD points at, or None on a branch with no commits yet
+pub fn current_head(repo: &path::Path) -> result::Result<Option<String>> {
+    let output = process::Command::new("git")
+        .args(["rev-parse", "--verify", "--quiet", "HEAD"])
+        .current_dir(repo)
+        .output()?;
+
+    if output.status.success() {
+        let head = String::from_utf8_lossy(&output.stdout);
+        Ok(Some(head.trim().to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+//------------------------------------------------------------------------------
+// Whether the revision names a commit in the repo
+pub fn has_commit(repo: &path::Path, rev: &str) -> result::Result<bool> {
+    let output = process::Command::new("git")
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{0}^{{commit}}", rev))
+        .current_dir(repo)
+        .output()?;
+
+    Ok(output.status.success())
+}
+
+//------------------------------------------------------------------------------
+pub fn local_branches(repo: &path::Path) -> result::Result<Vec<String>> {
+    let output = process::Command::new("git")
+        .args(["for-each-ref", "--format=%(refname:short)", "refs/heads"])
+        .current_dir(repo)
+        .output()?;
+
+    io::write_to_stderr(repo, &output.stderr)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().map(|line| line.to_string()).collect())
+}
+
+//------------------------------------------------------------------------------
+// The tracked files at or under the path, as git names them
+pub fn tracked_files(
+    repo: &path::Path,
+    relative_path: &str,
+) -> result::Result<Vec<Vec<u8>>> {
+    let output = process::Command::new("git")
+        .args(["ls-files", "-z", "--", relative_path])
+        .current_dir(repo)
+        .output()?;
+
+    io::write_to_stderr(repo, &output.stderr)?;
+
+    Ok(output
+        .stdout
+        .split(|byte| *byte == 0)
+        .filter(|file| !file.is_empty())
+        .map(|file| file.to_vec())
+        .collect())
+}
+
+//------------------------------------------------------------------------------
+// Stage just these files, as they are in the working copy. Unlike 'git add'
+// of their directory, nothing untracked next to them comes along.
+pub fn stage_files(
+    repo: &path::Path,
+    files: &[Vec<u8>],
+) -> result::Result<bool> {
+    let mut child = process::Command::new("git")
+        .args(["update-index", "--add", "--remove", "-z", "--stdin"])
+        .current_dir(repo)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = result::get(child.stdin.take())?;
+    for file in files {
+        stdin.write_all(file)?;
+        stdin.write_all(b"\0")?;
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    io::write_to_stderr(repo, &output.stderr)?;
+
+    Ok(output.status.success())
+}
@@ -2,7 +2,7 @@ use super::path;
 use super::result;
 //------------------------------------------------------------------------------
 use colored::*;
-use std;
+use std::io::{BufRead, BufReader, Read, Write};
 
 //------------------------------------------------------------------------------
 // Usage
@@ -14,6 +14,20 @@ USAGE:
 OPTIONS:
     -p, --path <regex>        Filter by repo file path using given expression
     -b, --branch <regex>      Filter by current branch using given expression
+    -s, --stream              Print output line by line as it arrives,
+                              prefixed with the repo (go and cmd only)
+    -o, --ordered             Print the output of each repo sorted by repo
+                              path, rather than as each repo finishes
+    -t, --timeout <duration>  Kill the commands running in a repo if they
+                              take longer than this (ie 30s, 5m)
+        --retry <count>       Run a command that failed or timed out again,
+                              up to this many times (go, cmd and clone)
+        --retry-delay <duration>
+                              How long to wait before retrying (default 1s)
+        --fail-fast           Stop everything once a repo doesn't succeed
+        --topo                Run the repos in waves, each repo after the
+                              repos listed in its .git-poly-depends file
+                              (not clone)
 
 SUBCOMMANDS
     go <git command>          Execute a git command in each repo
@@ -21,15 +35,57 @@ SUBCOMMANDS
     ls                        List all the git repos discovered
 
     clone                     Clone the repositories listed in stdin
-    add [-u] [<pathspec>...]  Add file contents to the index of it's repo
-    commit [-m] <message>     Record changes to the repository
-    grep <pattern>            Print lines matching a pattern
+    add [-u] [-A] [-p] [<pathspec>...]
+                              Add file contents to the index of it's repo,
+                              directories and globs can span several repos,
+                              -u/-A without a pathspec add in every repo,
+                              -p picks the hunks to add across all the repos
+    commit [<options>]        Record changes to the repository, the editor is
+                              opened once when no message is given
+                              (-m <msg>, -F <file>, --amend, -a, --signoff,
+                              --no-verify, --author <author>, --atomic)
+    grep [<options>] [--type <type>] <pattern> [--] [<pathspec>...]
+                              Print lines matching a pattern, any git grep
+                              option can be given (-i, -n, -w, -E, -l, -c,
+                              -C <n>, -e <pattern> --and ...), --rev <ref>
+                              searches a revision and --all-branches every
+                              local branch instead of the working tree,
+                              --count-by repo|file|branch prints a table
+                              of the number of matches, --json as json
     ls-files                  Show information about files in the index and the working tree
-    mv <from> <to>            Move or rename a file, a directory, or a symlink
-    reset                     Reset current HEAD to the specified state
+    mv [-n] [-f] [--with-history] <from>... <to>
+                              Move or rename a file, a directory, or a
+                              symlink, -f overwrites the dest, -n only shows
+                              what would move, --with-history replays its
+                              commits when it moves to another repo
+    reset [--soft|--mixed|--hard] [-q] [-y] [<commit>] [--] [<pathspec>...]
+                              Reset current HEAD to the specified state in
+                              each repo, --hard asks first unless -y is
+                              given, pathspecs are unstaged as with add
+    restore [--staged] [--worktree] [--source <tree>] [-n] <pathspec>...
+                              Discard changes to files in the repos they're
+                              in, directories and globs can span several
+                              repos, -n lists what would be restored
+    show-change <id> [<log options>...]
+                              Show the commits made together by one commit,
+                              found by their Poly-Change-Id trailer
     status                    Show the merged working tree status of all the repos
 
-    replace <from> <to>       Find and replace all occurances of FROM with TO
+    replace [-n] [-i] [-U] [-F] [--ignore-case] [-w] [--type <type>]
+            <from> <to> [-- <pathspec>...]
+                              Find and replace all occurances of FROM with TO,
+                              -n shows the changes as a diff without making
+                              them, -i asks about each match, -U lets FROM
+                              span lines, -F takes FROM and TO literally,
+                              -w only matches whole words, --type and
+                              pathspecs after -- pick the files
+    rename-symbol [-n] [--type <type>] <from> <to> [-- <pathspec>...]
+                              Rename the files with the word FROM in their
+                              name and every reference to FROM, -n shows
+                              what would change
+    undo [-f]                 Revert the last replace, rename-symbol or mv
+                              across all the repos, -f reverts files
+                              written since too
 ";
 
 //------------------------------------------------------------------------------
@@ -43,26 +99,59 @@ pub fn usage() {
     println!("{0}", USAGE);
 }
 
+//------------------------------------------------------------------------------
+// Ask a question on stdout, None when stdin has closed
+pub fn prompt(question: &str) -> result::Result<Option<String>> {
+    print!("{0}", question.blue().bold());
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+        println!();
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_string()))
+}
+
 //------------------------------------------------------------------------------
 pub fn write_to_out(
     handle: &mut dyn std::io::Write,
-    repo: &path::PathBuf,
+    repo: &path::Path,
     output: &[u8],
 ) -> result::Result<()> {
-    let display = result::get(repo.as_path().to_str())?;
+    let display = result::get(repo.to_str())?;
 
     writeln!(handle, "{0}", display.cyan())?;
-    handle.write_all(&output)?;
+    handle.write_all(output)?;
     writeln!(handle)?;
 
     Ok(())
 }
 
 //------------------------------------------------------------------------------
-pub fn write_to_stdout(
-    repo: &path::PathBuf,
+pub fn write_to_buffer(
+    buffer: &mut Vec<u8>,
+    repo: &path::Path,
     output: &[u8],
 ) -> result::Result<()> {
+    if !output.is_empty() {
+        write_to_out(buffer, repo, output)?;
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Write a line of output as it is, whether or not it's UTF-8, ending it with
+// a newline if it doesn't have one
+pub fn write_line(buffer: &mut Vec<u8>, line: &[u8]) {
+    buffer.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        buffer.push(b'\n');
+    }
+}
+
+//------------------------------------------------------------------------------
+pub fn write_to_stdout(repo: &path::Path, output: &[u8]) -> result::Result<()> {
     // stdout
     if !output.is_empty() {
         let stdout = std::io::stdout();
@@ -75,10 +164,7 @@ pub fn write_to_stdout(
 }
 
 //------------------------------------------------------------------------------
-pub fn write_to_stderr(
-    repo: &path::PathBuf,
-    output: &[u8],
-) -> result::Result<()> {
+pub fn write_to_stderr(repo: &path::Path, output: &[u8]) -> result::Result<()> {
     // stderr
     if !output.is_empty() {
         let stderr = std::io::stderr();
@@ -89,3 +175,90 @@ pub fn write_to_stderr(
     }
     Ok(())
 }
+
+//------------------------------------------------------------------------------
+// Streaming
+//------------------------------------------------------------------------------
+const REPO_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Yellow,
+    Color::Green,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
+
+//------------------------------------------------------------------------------
+fn repo_color(repo: &path::Path) -> Color {
+    // The same repo always gets the same colour, so it can be followed
+    // through the interleaved output of a long running command.
+    let hash = repo.to_string_lossy().bytes().fold(0usize, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+
+    REPO_COLORS[hash % REPO_COLORS.len()]
+}
+
+//------------------------------------------------------------------------------
+pub fn write_prefixed_line(
+    handle: &mut dyn std::io::Write,
+    repo: &path::Path,
+    line: &[u8],
+) -> result::Result<()> {
+    let display = result::get(repo.to_str())?;
+
+    write!(handle, "{0} | ", display.color(repo_color(repo)))?;
+    handle.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        writeln!(handle)?;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+pub fn stream_lines(
+    repo: &path::Path,
+    input: impl Read,
+    to_stderr: bool,
+) -> result::Result<()> {
+    let mut reader = BufReader::new(input);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? != 0 {
+        if to_stderr {
+            write_prefixed_line(&mut std::io::stderr().lock(), repo, &line)?;
+        } else {
+            write_prefixed_line(&mut std::io::stdout().lock(), repo, &line)?;
+        }
+        line.clear();
+    }
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Write the file next to where it goes and rename it over the top, so
+// nothing ever sees it half written. An existing file keeps its permissions.
+pub fn write_atomically(
+    file_path: &path::Path,
+    contents: &[u8],
+) -> result::Result<()> {
+    let file_name = result::get(file_path.file_name())?.to_string_lossy();
+    let temp_path = file_path.with_file_name(format!(
+        ".{0}.git-p-{1}.tmp",
+        file_name,
+        std::process::id()
+    ));
+
+    let written = std::fs::write(&temp_path, contents).and_then(|_| {
+        if let Ok(metadata) = std::fs::metadata(file_path) {
+            std::fs::set_permissions(&temp_path, metadata.permissions())?;
+        }
+        std::fs::rename(&temp_path, file_path)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    Ok(written?)
+}
@@ -0,0 +1,462 @@
+use super::error::Error;
+use super::path;
+use super::result::Result;
+//------------------------------------------------------------------------------
+use std::fs;
+use std::io::Write;
+use std::process;
+
+//------------------------------------------------------------------------------
+// The last operation that changed files is journaled in this directory, in
+// the directory git poly was run from, so that it can be undone. Inside a
+// repo it goes in the git directory instead, as JOURNAL_GIT_DIR.
+pub const JOURNAL_DIR: &str = ".git-poly-undo";
+const JOURNAL_GIT_DIR: &str = "git-poly-undo";
+const JOURNAL_FILE: &str = "journal";
+const BACKUP_DIR: &str = "backups";
+
+//------------------------------------------------------------------------------
+// FNV-1a, to tell whether a file has changed since it was written
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+//------------------------------------------------------------------------------
+// Copy a file or a whole directory
+pub fn copy_all(from: &path::Path, to: &path::Path) -> Result<()> {
+    if fs::symlink_metadata(from)?.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Where the journal goes. In a work tree it would show up as untracked, and
+// be staged by 'add -A', so there it's kept with the repo's git directory.
+fn journal_dir() -> path::PathBuf {
+    let output = process::Command::new("git")
+        .args(["rev-parse", "--git-common-dir"])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            let git_dir = String::from_utf8_lossy(&output.stdout);
+            path::PathBuf::from(git_dir.trim()).join(JOURNAL_GIT_DIR)
+        }
+        _ => path::PathBuf::from(JOURNAL_DIR),
+    }
+}
+
+//------------------------------------------------------------------------------
+// Move
+//------------------------------------------------------------------------------
+// A file or directory that was moved, all the paths are absolute
+pub struct Move {
+    pub from_repo: path::PathBuf,
+    pub from_rel: String,
+    pub to_repo: path::PathBuf,
+    pub to_rel: String,
+    pub tracked: bool,
+    // Where HEAD was in the destination before the history was replayed, and
+    // where it was after
+    pub history: Option<(Option<String>, String)>,
+}
+
+//------------------------------------------------------------------------------
+// Entry
+//------------------------------------------------------------------------------
+pub enum Entry {
+    // A file that was written, with the backup of what was there before and
+    // the hash of what was written
+    Write {
+        path: path::PathBuf,
+        backup: String,
+        hash: u64,
+    },
+    Move(Move),
+    // What was at the dest of a move before it was written over, with its
+    // backup and whether git tracked it
+    Overwrite {
+        repo: path::PathBuf,
+        rel: String,
+        backup: String,
+        tracked: bool,
+    },
+}
+
+//------------------------------------------------------------------------------
+// The bytes of a path, as they are on unix
+#[cfg(unix)]
+fn path_bytes(path: &path::Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+//------------------------------------------------------------------------------
+#[cfg(not(unix))]
+fn path_bytes(path: &path::Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
+
+//------------------------------------------------------------------------------
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<path::PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(path::PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+//------------------------------------------------------------------------------
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<path::PathBuf> {
+    let path = String::from_utf8(bytes).map_err(|_| Error::BadJournal)?;
+    Ok(path::PathBuf::from(path))
+}
+
+//------------------------------------------------------------------------------
+// A field of a line, with anything that would split the line, or isn't
+// printable ascii, escaped so any name survives the trip
+fn escape(bytes: &[u8]) -> String {
+    let mut field = String::new();
+    for byte in bytes {
+        match byte {
+            b'\\' => field.push_str("\\\\"),
+            b'\t' => field.push_str("\\t"),
+            b'\n' => field.push_str("\\n"),
+            b' '..=b'~' => field.push(char::from(*byte)),
+            _ => field.push_str(&format!("\\x{0:02x}", byte)),
+        }
+    }
+    field
+}
+
+//------------------------------------------------------------------------------
+fn unescape(field: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some((byte, tail)) = rest.split_first() {
+        rest = tail;
+        if *byte != b'\\' {
+            bytes.push(*byte);
+            continue;
+        }
+        let (escaped, tail) = rest.split_first().ok_or(Error::BadJournal)?;
+        rest = tail;
+        match escaped {
+            b'\\' => bytes.push(b'\\'),
+            b't' => bytes.push(b'\t'),
+            b'n' => bytes.push(b'\n'),
+            b'x' if rest.len() >= 2 => {
+                let hex = std::str::from_utf8(&rest[..2])
+                    .map_err(|_| Error::BadJournal)?;
+                bytes.push(
+                    u8::from_str_radix(hex, 16)
+                        .map_err(|_| Error::BadJournal)?,
+                );
+                rest = &rest[2..];
+            }
+            _ => return Err(Error::BadJournal),
+        }
+    }
+    Ok(bytes)
+}
+
+//------------------------------------------------------------------------------
+fn escape_path(path: &path::Path) -> String {
+    escape(&path_bytes(path))
+}
+
+//------------------------------------------------------------------------------
+fn unescape_path(field: &str) -> Result<path::PathBuf> {
+    path_from_bytes(unescape(field)?)
+}
+
+//------------------------------------------------------------------------------
+fn unescape_string(field: &str) -> Result<String> {
+    String::from_utf8(unescape(field)?).map_err(|_| Error::BadJournal)
+}
+
+//------------------------------------------------------------------------------
+fn optional(field: &Option<String>) -> &str {
+    field.as_deref().unwrap_or("-")
+}
+
+//------------------------------------------------------------------------------
+fn from_optional(field: &str) -> Option<String> {
+    match field {
+        "-" => None,
+        _ => Some(field.to_string()),
+    }
+}
+
+//------------------------------------------------------------------------------
+impl Entry {
+    // One line of tab separated fields, the names in them escaped
+    fn to_line(&self) -> String {
+        match self {
+            Entry::Write { path, backup, hash } => {
+                format!(
+                    "write\t{0:016x}\t{1}\t{2}",
+                    hash,
+                    backup,
+                    escape_path(path)
+                )
+            }
+            Entry::Move(moved) => {
+                let (previous_head, head) = match &moved.history {
+                    Some((previous_head, head)) => {
+                        (previous_head.clone(), Some(head.clone()))
+                    }
+                    None => (None, None),
+                };
+                format!(
+                    "move\t{0}\t{1}\t{2}\t{3}\t{4}\t{5}\t{6}",
+                    escape_path(&moved.from_repo),
+                    escape(moved.from_rel.as_bytes()),
+                    escape_path(&moved.to_repo),
+                    escape(moved.to_rel.as_bytes()),
+                    moved.tracked,
+                    optional(&previous_head),
+                    optional(&head)
+                )
+            }
+            Entry::Overwrite {
+                repo,
+                rel,
+                backup,
+                tracked,
+            } => format!(
+                "overwrite\t{0}\t{1}\t{2}\t{3}",
+                escape_path(repo),
+                escape(rel.as_bytes()),
+                backup,
+                tracked
+            ),
+        }
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["write", hash, backup, path] => Ok(Entry::Write {
+                path: unescape_path(path)?,
+                backup: backup.to_string(),
+                hash: u64::from_str_radix(hash, 16)
+                    .map_err(|_| Error::BadJournal)?,
+            }),
+            ["move", from_repo, from_rel, to_repo, to_rel, tracked, previous_head, head] => {
+                Ok(Entry::Move(Move {
+                    from_repo: unescape_path(from_repo)?,
+                    from_rel: unescape_string(from_rel)?,
+                    to_repo: unescape_path(to_repo)?,
+                    to_rel: unescape_string(to_rel)?,
+                    tracked: *tracked == "true",
+                    history: from_optional(head)
+                        .map(|head| (from_optional(previous_head), head)),
+                }))
+            }
+            ["overwrite", repo, rel, backup, tracked] => Ok(Entry::Overwrite {
+                repo: unescape_path(repo)?,
+                rel: unescape_string(rel)?,
+                backup: backup.to_string(),
+                tracked: *tracked == "true",
+            }),
+            _ => Err(Error::BadJournal),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Journal
+//------------------------------------------------------------------------------
+// The journal of the files an operation changes. It's only started once
+// something is recorded, so an operation that changes nothing leaves the
+// last journal alone, and each entry is written as soon as it's recorded.
+pub struct Journal {
+    pub operation: String,
+    pub entries: Vec<Entry>,
+    dir: path::PathBuf,
+    started: bool,
+    backups: usize,
+}
+
+//------------------------------------------------------------------------------
+impl Journal {
+    pub fn new(operation: &str) -> Self {
+        Journal {
+            operation: operation.to_string(),
+            entries: Vec::new(),
+            dir: journal_dir(),
+            started: false,
+            backups: 0,
+        }
+    }
+
+    // Throw away the journal of the last operation and start this one
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            if self.dir.exists() {
+                fs::remove_dir_all(&self.dir)?;
+            }
+            fs::create_dir_all(self.dir.join(BACKUP_DIR))?;
+            fs::write(
+                self.dir.join(JOURNAL_FILE),
+                format!("operation\t{0}\n", self.operation),
+            )?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    fn next_backup(&mut self) -> String {
+        self.backups += 1;
+        format!("{0}", self.backups)
+    }
+
+    pub fn backup_path(&self, backup: &str) -> path::PathBuf {
+        self.dir.join(BACKUP_DIR).join(backup)
+    }
+
+    // Keep the contents of a file, returning the name of the backup
+    pub fn keep(&mut self, contents: &[u8]) -> Result<String> {
+        self.start()?;
+        let backup = self.next_backup();
+        fs::write(self.backup_path(&backup), contents)?;
+        Ok(backup)
+    }
+
+    // Keep a copy of a file or a directory, returning the name of the backup
+    pub fn keep_path(&mut self, path: &path::Path) -> Result<String> {
+        self.start()?;
+        let backup = self.next_backup();
+        copy_all(path, &self.backup_path(&backup))?;
+        Ok(backup)
+    }
+
+    pub fn record(&mut self, entry: Entry) -> Result<()> {
+        self.start()?;
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(JOURNAL_FILE))?;
+        writeln!(file, "{0}", entry.to_line())?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // The journal of the last operation, if there is one
+    pub fn load() -> Result<Option<Self>> {
+        let dir = journal_dir();
+        let text = match fs::read_to_string(dir.join(JOURNAL_FILE)) {
+            Ok(text) => text,
+            Err(_) => return Ok(None),
+        };
+
+        let mut lines = text.lines();
+        let operation =
+            match lines.next().and_then(|l| l.strip_prefix("operation\t")) {
+                Some(operation) => operation.to_string(),
+                None => return Err(Error::BadJournal),
+            };
+
+        let mut entries = Vec::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            entries.push(Entry::from_line(line)?);
+        }
+
+        Ok(Some(Journal {
+            operation,
+            entries,
+            dir,
+            started: true,
+            backups: 0,
+        }))
+    }
+
+    // Once it's been undone there's nothing left to undo
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    fn round_trip(entry: &Entry) -> Entry {
+        let line = entry.to_line();
+        assert!(!line.contains('\n'));
+        Entry::from_line(&line).unwrap()
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn names_with_tabs_and_newlines_survive_a_line() {
+        let entry = round_trip(&Entry::Move(Move {
+            from_repo: path::PathBuf::from("/work/a\trepo"),
+            from_rel: "dir\\x/new\nline".to_string(),
+            to_repo: path::PathBuf::from("/work/b"),
+            to_rel: "caf\u{e9}.txt".to_string(),
+            tracked: true,
+            history: Some((None, "abc".to_string())),
+        }));
+        match entry {
+            Entry::Move(moved) => {
+                assert_eq!(moved.from_repo, path::Path::new("/work/a\trepo"));
+                assert_eq!(moved.from_rel, "dir\\x/new\nline");
+                assert_eq!(moved.to_repo, path::Path::new("/work/b"));
+                assert_eq!(moved.to_rel, "caf\u{e9}.txt");
+                assert!(moved.tracked);
+                assert_eq!(moved.history, Some((None, "abc".to_string())));
+            }
+            _ => panic!("not a move"),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_survive_a_line() {
+        use std::os::unix::ffi::OsStringExt;
+        let path = path::PathBuf::from(std::ffi::OsString::from_vec(
+            b"/work/a/\xff\xfe.txt".to_vec(),
+        ));
+        let entry = round_trip(&Entry::Write {
+            path: path.clone(),
+            backup: "1".to_string(),
+            hash: hash(b"contents"),
+        });
+        match entry {
+            Entry::Write {
+                path: read_path,
+                backup,
+                hash: read_hash,
+            } => {
+                assert_eq!(read_path, path);
+                assert_eq!(backup, "1");
+                assert_eq!(read_hash, hash(b"contents"));
+            }
+            _ => panic!("not a write"),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn bad_escapes_are_rejected() {
+        assert!(Entry::from_line("write\t0\t1\t/a\\q").is_err());
+        assert!(Entry::from_line("write\t0\t1\t/a\\").is_err());
+        assert!(Entry::from_line("write\t0\t1\t/a\\x4").is_err());
+    }
+}
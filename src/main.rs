@@ -4,34 +4,43 @@
 mod branch_regex;
 mod channel;
 mod command;
+mod edit;
 mod error;
+mod execute;
+mod filetype;
 mod filter;
 mod git;
 mod io;
+mod journal;
+mod patch;
 mod path;
+mod pathspec;
 mod repoiterator;
 mod result;
 mod status;
-//------------------------------------------------------------------------------
-use regex;
-use std;
+mod topo;
 
 //------------------------------------------------------------------------------
 struct Flags {
     path: regex::Regex,
     branch: branch_regex::BranchRegex,
+    execute: execute::Options,
 }
 
 //------------------------------------------------------------------------------
 impl Flags {
     pub fn new() -> result::Result<Self> {
         let path = regex::Regex::new(r".*")?;
-        Ok(Flags { path, branch: None })
+        Ok(Flags {
+            path,
+            branch: None,
+            execute: execute::Options::default(),
+        })
     }
 }
 
 //------------------------------------------------------------------------------
-fn main() -> result::Result<()> {
+fn run() -> result::Result<()> {
     // The flags
     let mut flags = Flags::new()?;
 
@@ -76,6 +85,76 @@ fn main() -> result::Result<()> {
                     flags.branch = Some(regex::Regex::new(&(args[index + 1]))?);
                     skip = 1;
                 }
+                "--stream" | "-s" => {
+                    if flags.execute.ordered {
+                        io::argument_error(
+                            "--stream can't be combined with --ordered",
+                        );
+                    }
+                    flags.execute.stream = true;
+                }
+                "--timeout" | "-t" => {
+                    if (index + 1) == args.len() {
+                        io::argument_error(
+                            "--timeout requires a duration \
+                             (ie --timeout 30s)",
+                        );
+                    }
+                    flags.execute.timeout =
+                        execute::parse_duration(&args[index + 1]);
+                    if flags.execute.timeout.is_none() {
+                        io::argument_error(
+                            "--timeout expects a duration such as \
+                             500ms, 30s, 5m or 1h",
+                        );
+                    }
+                    skip = 1;
+                }
+                "--retry" => {
+                    if (index + 1) == args.len() {
+                        io::argument_error(
+                            "--retry requires a number of retries \
+                             (ie --retry 3)",
+                        );
+                    }
+                    match args[index + 1].parse() {
+                        Ok(retries) => flags.execute.retries = retries,
+                        Err(_) => io::argument_error(
+                            "--retry expects a whole number (ie --retry 3)",
+                        ),
+                    }
+                    skip = 1;
+                }
+                "--retry-delay" => {
+                    if (index + 1) == args.len() {
+                        io::argument_error(
+                            "--retry-delay requires a duration \
+                             (ie --retry-delay 5s)",
+                        );
+                    }
+                    match execute::parse_duration(&args[index + 1]) {
+                        Some(delay) => flags.execute.retry_delay = delay,
+                        None => io::argument_error(
+                            "--retry-delay expects a duration such as \
+                             500ms, 30s, 5m or 1h",
+                        ),
+                    }
+                    skip = 1;
+                }
+                "--fail-fast" => {
+                    flags.execute.fail_fast = true;
+                }
+                "--topo" => {
+                    flags.execute.topo = true;
+                }
+                "--ordered" | "-o" => {
+                    if flags.execute.stream {
+                        io::argument_error(
+                            "--ordered can't be combined with --stream",
+                        );
+                    }
+                    flags.execute.ordered = true;
+                }
                 // Sub-commands
                 "go" => {
                     if index + 1 == args.len() {
@@ -83,7 +162,12 @@ fn main() -> result::Result<()> {
                             "go requires at least one git command",
                         );
                     }
-                    command::go::run(&flags.path, &flags.branch, index + 1)?;
+                    command::go::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute,
+                        index + 1,
+                    )?;
                     break;
                 }
                 "cmd" => {
@@ -92,7 +176,12 @@ fn main() -> result::Result<()> {
                             "cmd requires at least one shell command",
                         );
                     }
-                    command::cmd::run(&flags.path, &flags.branch, index + 1)?;
+                    command::cmd::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute,
+                        index + 1,
+                    )?;
                     break;
                 }
                 "add" => {
@@ -101,22 +190,29 @@ fn main() -> result::Result<()> {
 Maybe you wanted to say 'git add .'?";
                         io::argument_error(error);
                     }
-                    command::add::run(&flags.path, index + 1)?;
+                    command::add::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute.without_retries(),
+                        index + 1,
+                    )?;
                     break;
                 }
                 "grep" => {
-                    if index + 1 == args.len() {
-                        io::argument_error("Please provide the expression you would like to grep for");
-                    }
                     command::grep::run(
                         &flags.path,
                         &flags.branch,
-                        args[index + 1].as_str(),
+                        &flags.execute.without_retries(),
+                        &command::grep::parse_args(&args[index + 1..]),
                     )?;
                     break;
                 }
                 "ls-files" => {
-                    command::ls_files::run(&flags.path, &flags.branch)?;
+                    command::ls_files::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute.without_retries(),
+                    )?;
                     break;
                 }
                 "ls" => {
@@ -124,29 +220,62 @@ Maybe you wanted to say 'git add .'?";
                     break;
                 }
                 "clone" => {
-                    command::clone::run(&flags.path)?;
+                    // The repos don't exist yet to read their dependencies
+                    if flags.execute.topo {
+                        io::argument_error("clone can't be run with --topo");
+                    }
+                    command::clone::run(&flags.path, &flags.execute)?;
                     break;
                 }
                 "commit" => {
-                    if index + 2 >= args.len() {
+                    let options =
+                        command::commit::parse_args(&args[index + 1..]);
+                    command::commit::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute.without_retries(),
+                        &options,
+                    )?;
+                    break;
+                }
+                "show-change" => {
+                    if index + 1 == args.len() {
                         io::argument_error(
-                            "commit requires at least arguments -m and a message",
+                            "show-change requires the Poly-Change-Id to show",
                         );
                     }
-
-                    if args[index + 1] != "-m" {
-                        io::argument_error("commit requires -m");
+                    let id = args[index + 1].as_str();
+                    if !id.chars().all(|c| c.is_ascii_hexdigit()) {
+                        io::argument_error(
+                            "show-change expects a Poly-Change-Id, \
+                             which is hexadecimal",
+                        );
                     }
-
-                    command::commit::run(
+                    command::show_change::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute.without_retries(),
+                        id,
+                        &args[index + 2..],
+                    )?;
+                    break;
+                }
+                "restore" => {
+                    command::restore::run(
                         &flags.path,
                         &flags.branch,
-                        args[index + 2].as_str(),
+                        &flags.execute.without_retries(),
+                        &command::restore::parse_args(&args[index + 1..]),
                     )?;
                     break;
                 }
                 "reset" => {
-                    command::reset::run(&flags.path, &flags.branch)?;
+                    command::reset::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute.without_retries(),
+                        &command::reset::parse_args(&args[index + 1..]),
+                    )?;
                     break;
                 }
                 "status" => {
@@ -154,25 +283,35 @@ Maybe you wanted to say 'git add .'?";
                     break;
                 }
                 "mv" => {
-                    if index + 2 >= args.len() {
-                        io::argument_error("mv requires a source and a dest");
-                    }
-                    command::mv::run(&args[index + 1], &args[index + 2])?;
+                    command::mv::run(&command::mv::parse_args(
+                        &args[index + 1..],
+                    ))?;
                     break;
                 }
                 "replace" => {
-                    if index + 2 >= args.len() {
-                        io::argument_error(
-                            "replace requires at least two arguments",
-                        );
-                    }
                     command::replace::run(
                         &flags.path,
                         &flags.branch,
-                        index + 1,
+                        &flags.execute.without_retries(),
+                        &command::replace::parse_args(&args[index + 1..]),
                     )?;
                     break;
                 }
+                "rename-symbol" => {
+                    command::rename_symbol::run(
+                        &flags.path,
+                        &flags.branch,
+                        &flags.execute.without_retries(),
+                        &command::rename_symbol::parse_args(&args[index + 1..]),
+                    )?;
+                    break;
+                }
+                "undo" => {
+                    command::undo::run(&command::undo::parse_args(
+                        &args[index + 1..],
+                    ))?;
+                    break;
+                }
                 _ => io::argument_error("argument not recognised"),
             }
         } else {
@@ -182,3 +321,11 @@ Maybe you wanted to say 'git add .'?";
 
     Ok(())
 }
+
+//------------------------------------------------------------------------------
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("error: {0}", error);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,222 @@
+use super::io::prompt;
+use super::path;
+use super::result::Result;
+//------------------------------------------------------------------------------
+use colored::*;
+
+//------------------------------------------------------------------------------
+// FilePatch
+//------------------------------------------------------------------------------
+// The changes to one file in the output of 'git diff'. The header is
+// everything up to the first hunk. The lines are kept as they were, line
+// endings and all, so the patch still applies when they aren't UTF-8 or end
+// in CRLF.
+pub struct FilePatch {
+    pub path: path::PathBuf,
+    pub header: Vec<Vec<u8>>,
+    pub hunks: Vec<Vec<Vec<u8>>>,
+}
+
+//------------------------------------------------------------------------------
+// A line without its line ending
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+//------------------------------------------------------------------------------
+fn path_from(name: &[u8]) -> path::PathBuf {
+    path::PathBuf::from(
+        String::from_utf8_lossy(trim_line_ending(name)).into_owned(),
+    )
+}
+
+//------------------------------------------------------------------------------
+impl FilePatch {
+    fn new(header_line: &[u8]) -> Self {
+        // 'diff --git a/<path> b/<path>', replaced by the ---/+++ lines when
+        // there are any, as they are unambiguous
+        let line = trim_line_ending(header_line);
+        let name = line
+            .windows(3)
+            .rposition(|window| window == b" b/")
+            .map_or(line, |position| &line[position + 3..]);
+
+        FilePatch {
+            path: path_from(name),
+            header: vec![header_line.to_vec()],
+            hunks: Vec::new(),
+        }
+    }
+
+    // The patch with just the chosen hunks, to give to 'git apply'
+    pub fn with_hunks(&self, chosen: &[bool]) -> Vec<u8> {
+        let mut patch = self.header.concat();
+        for (hunk, _) in self
+            .hunks
+            .iter()
+            .zip(chosen.iter())
+            .filter(|(_, chosen)| **chosen)
+        {
+            for line in hunk {
+                patch.extend_from_slice(line);
+            }
+        }
+        patch
+    }
+
+    // A hunk as it's shown, rather than as it's applied
+    pub fn display_hunk(&self, index: usize) -> Vec<String> {
+        self.hunks[index]
+            .iter()
+            .map(|line| String::from_utf8_lossy(trim_line_ending(line)))
+            .map(|line| line.into_owned())
+            .collect()
+    }
+}
+
+//------------------------------------------------------------------------------
+// Split the output of 'git diff' up into files and hunks
+pub fn parse(diff: &[u8]) -> Vec<FilePatch> {
+    let mut files: Vec<FilePatch> = Vec::new();
+
+    for line in diff.split_inclusive(|byte| *byte == b'\n') {
+        if line.starts_with(b"diff --git ") {
+            files.push(FilePatch::new(line));
+            continue;
+        }
+
+        let file = match files.last_mut() {
+            Some(file) => file,
+            None => continue,
+        };
+
+        if line.starts_with(b"@@") {
+            file.hunks.push(vec![line.to_vec()]);
+        } else if let Some(hunk) = file.hunks.last_mut() {
+            hunk.push(line.to_vec());
+        } else {
+            if let Some(name) = line.strip_prefix(b"+++ b/") {
+                file.path = path_from(name);
+            } else if let Some(name) = line.strip_prefix(b"--- a/") {
+                file.path = path_from(name);
+            }
+            file.header.push(line.to_vec());
+        }
+    }
+
+    files
+}
+
+//------------------------------------------------------------------------------
+pub fn write_header(
+    handle: &mut dyn std::io::Write,
+    display_path: &str,
+) -> std::io::Result<()> {
+    writeln!(handle, "{0}", format!("--- a/{0}", display_path).bold())?;
+    writeln!(handle, "{0}", format!("+++ b/{0}", display_path).bold())
+}
+
+//------------------------------------------------------------------------------
+pub fn write_hunk(
+    handle: &mut dyn std::io::Write,
+    hunk: &[String],
+) -> std::io::Result<()> {
+    for line in hunk {
+        if line.starts_with("@@") {
+            writeln!(handle, "{0}", line.cyan())?;
+        } else if line.starts_with('+') {
+            writeln!(handle, "{0}", line.green())?;
+        } else if line.starts_with('-') {
+            writeln!(handle, "{0}", line.red())?;
+        } else {
+            writeln!(handle, "{0}", line)?;
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+pub fn print_hunk(hunk: &[String]) -> std::io::Result<()> {
+    write_hunk(&mut std::io::stdout().lock(), hunk)
+}
+
+//------------------------------------------------------------------------------
+// Chooser
+//------------------------------------------------------------------------------
+// Asks about the hunks one at a time, as 'git add -p' does, numbering them
+// across every file. The question and help say what choosing a hunk does.
+pub struct Chooser {
+    question: &'static str,
+    help: &'static str,
+    number: usize,
+    total: usize,
+    pub quit: bool,
+}
+
+//------------------------------------------------------------------------------
+impl Chooser {
+    pub fn new(
+        question: &'static str,
+        help: &'static str,
+        total: usize,
+    ) -> Self {
+        Chooser {
+            question,
+            help,
+            number: 0,
+            total,
+            quit: false,
+        }
+    }
+
+    // Which of the hunks of one file were chosen, showing each one before
+    // asking about it. Once quit, nothing more is chosen.
+    pub fn choose<F: FnMut(usize) -> Result<()>>(
+        &mut self,
+        count: usize,
+        mut show: F,
+    ) -> Result<Vec<bool>> {
+        let mut chosen = vec![false; count];
+
+        let mut index = 0;
+        while index < count && !self.quit {
+            show(index)?;
+
+            let question = format!(
+                "({0}/{1}) {2} [y,n,q,a,d,?]? ",
+                self.number + 1,
+                self.total,
+                self.question
+            );
+            match prompt(&question)?.as_deref() {
+                Some("y") => chosen[index] = true,
+                Some("n") => (),
+                Some("a") => {
+                    for hunk in chosen.iter_mut().skip(index) {
+                        *hunk = true;
+                    }
+                    self.number += count - index;
+                    break;
+                }
+                Some("d") => {
+                    self.number += count - index;
+                    break;
+                }
+                Some("q") | None => {
+                    self.quit = true;
+                    continue;
+                }
+                _ => {
+                    println!("{0}", self.help.red());
+                    continue;
+                }
+            }
+
+            self.number += 1;
+            index += 1;
+        }
+
+        Ok(chosen)
+    }
+}
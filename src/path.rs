@@ -1,7 +1,12 @@
-use std;
-
 pub type Path = std::path::Path;
 pub type PathBuf = std::path::PathBuf;
 pub type StripPrefixError = std::path::StripPrefixError;
 
 pub type PathMsg = Option<std::path::PathBuf>;
+
+// Drop the '.' components, so './a/./b' and 'a/b' compare equal
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != std::path::Component::CurDir)
+        .collect()
+}
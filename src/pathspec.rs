@@ -0,0 +1,343 @@
+use super::branch_regex::BranchRegex;
+use super::error::Error;
+use super::filter;
+use super::git;
+use super::path;
+use super::repoiterator::RepoIterator;
+use super::result::Result;
+//------------------------------------------------------------------------------
+use std::collections::BTreeMap;
+use std::process;
+
+//------------------------------------------------------------------------------
+// The pathspecs to give git in each repo, relative to the repo
+pub type RepoPathspecs = BTreeMap<path::PathBuf, Vec<String>>;
+
+//------------------------------------------------------------------------------
+pub fn is_glob(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+//------------------------------------------------------------------------------
+// Convert a glob to a regex over the whole path. As with git's pathspecs,
+// the wildcards match '/' too.
+fn glob_to_regex(glob: &str) -> Result<regex::Regex> {
+    let mut expression = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                while chars.peek() == Some(&'*') {
+                    chars.next();
+                }
+                expression.push_str(".*");
+            }
+            '?' => expression.push('.'),
+            '[' => {
+                expression.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    expression.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        expression.push('\\');
+                    }
+                    expression.push(c);
+                }
+                expression.push(']');
+            }
+            _ => expression.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expression.push('$');
+
+    Ok(regex::Regex::new(&expression)?)
+}
+
+//------------------------------------------------------------------------------
+// The repos matching the filters, in a stable order
+pub fn filtered_repos(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+) -> Result<Vec<path::PathBuf>> {
+    let mut repos = Vec::new();
+    for repo in RepoIterator::new(regex) {
+        if let Some(pattern) = branch_regex {
+            if !filter::branch(pattern, &repo)? {
+                continue;
+            }
+        }
+        repos.push(repo);
+    }
+    repos.sort();
+    Ok(repos)
+}
+
+//------------------------------------------------------------------------------
+// The files git knows about in the repo, tracked or not, but not ignored
+fn list_files(repo: &path::Path) -> Result<Vec<String>> {
+    let output = process::Command::new("git")
+        .args([
+            "ls-files",
+            "-z",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+        ])
+        .current_dir(repo)
+        .output()?;
+
+    let mut files: Vec<String> = output
+        .stdout
+        .split(|b| *b == 0)
+        .filter(|file| !file.is_empty())
+        .map(|file| String::from_utf8_lossy(file).to_string())
+        .collect();
+    files.dedup();
+    Ok(files)
+}
+
+//------------------------------------------------------------------------------
+fn add(pathspecs: &mut RepoPathspecs, repo: &path::Path, pathspec: String) {
+    // Written the way the repo iterator would find it, so each repo only
+    // appears once
+    let repo = path::Path::new(".").join(path::normalize(repo));
+    let specs = pathspecs.entry(repo).or_default();
+    if !specs.contains(&pathspec) {
+        specs.push(pathspec);
+    }
+}
+
+//------------------------------------------------------------------------------
+// Where the bracket expression starting at '[' ends, as glob_to_regex reads
+// it
+fn bracket_end(glob: &[char], start: usize) -> usize {
+    let mut end = start + 1;
+    if glob.get(end) == Some(&'!') {
+        end += 1;
+    }
+    while end < glob.len() && glob[end] != ']' {
+        end += 1;
+    }
+    end.min(glob.len() - 1)
+}
+
+//------------------------------------------------------------------------------
+// The globs a path in a repo has to match for the repo's directory joined
+// with it to match the whole glob, ie the glob with the directory taken off
+// the front. Wildcards can match part or all of the directory, so there can
+// be several, or none when nothing in the repo can match.
+fn strip_glob_prefix(glob: &str, directory: &str) -> Result<Vec<String>> {
+    let glob: Vec<char> = glob.chars().collect();
+
+    // Every place in the glob the directory could have got up to
+    let mut positions = vec![0];
+    for c in format!("{0}/", directory).chars() {
+        let mut next = Vec::new();
+        let mut i = 0;
+        while i < positions.len() {
+            let position = positions[i];
+            i += 1;
+            match glob.get(position) {
+                Some('*') => {
+                    next.push(position);
+                    // Or the '*' matches nothing
+                    positions.push(position + 1);
+                }
+                Some('?') => next.push(position + 1),
+                Some('[') => {
+                    let end = bracket_end(&glob, position);
+                    let set: String = glob[position..=end].iter().collect();
+                    if glob_to_regex(&set)?.is_match(&c.to_string()) {
+                        next.push(end + 1);
+                    }
+                }
+                Some(literal) if *literal == c => next.push(position + 1),
+                _ => (),
+            }
+        }
+        next.sort_unstable();
+        next.dedup();
+        positions = next;
+    }
+
+    Ok(positions
+        .into_iter()
+        .filter(|position| *position < glob.len())
+        .map(|position| glob[position..].iter().collect())
+        .collect())
+}
+
+//------------------------------------------------------------------------------
+// A glob is given to git as it is, with the repo's directory taken off, as
+// git's pathspecs match across directories in the same way. The files are
+// only listed to check it matches something.
+fn resolve_glob(
+    pathspecs: &mut RepoPathspecs,
+    repos: &[path::PathBuf],
+    glob: &str,
+) -> Result<()> {
+    let glob = path::normalize(path::Path::new(glob));
+    let glob = glob.to_string_lossy();
+    let expression = glob_to_regex(&glob)?;
+
+    let mut matched = false;
+    for repo in repos {
+        let prefix = path::normalize(repo);
+        let matches = list_files(repo)?.iter().any(|file| {
+            expression.is_match(&prefix.join(file).to_string_lossy())
+        });
+        if !matches {
+            continue;
+        }
+        matched = true;
+
+        if prefix.as_os_str().is_empty() {
+            add(pathspecs, repo, glob.to_string());
+            continue;
+        }
+        for spec in strip_glob_prefix(&glob, &prefix.to_string_lossy())? {
+            add(pathspecs, repo, spec);
+        }
+    }
+
+    if !matched {
+        return Err(Error::PathspecDidNotMatch(glob.to_string()));
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+fn resolve_path(
+    pathspecs: &mut RepoPathspecs,
+    repos: &[path::PathBuf],
+    arg: &str,
+) -> Result<()> {
+    let path = path::PathBuf::from(arg);
+
+    // The repo the path is in
+    let inside = git::relative_to_repo(&path);
+    if let Ok((repo, relative_path)) = &inside {
+        add(pathspecs, repo, relative_path.clone());
+    }
+
+    // The repos within a directory
+    let mut covered = false;
+    if path.is_dir() {
+        let directory = path::normalize(&path);
+        for repo in repos {
+            let normalized = path::normalize(repo);
+            if normalized.starts_with(&directory) && normalized != directory {
+                add(pathspecs, repo, ".".to_string());
+                covered = true;
+            }
+        }
+    }
+
+    if inside.is_err() && !covered {
+        return Err(Error::NotInARepo(path));
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Work out which repos the paths and globs given on the command line are in.
+// A directory covers every repo below it, and a glob is matched against the
+// files of every repo, so they can both span several repos.
+pub fn resolve(
+    regex: &regex::Regex,
+    branch_regex: &BranchRegex,
+    args: &[String],
+) -> Result<RepoPathspecs> {
+    let repos = filtered_repos(regex, branch_regex)?;
+
+    let mut pathspecs = RepoPathspecs::new();
+    for arg in args {
+        if is_glob(arg) {
+            resolve_glob(&mut pathspecs, &repos, arg)?;
+        } else {
+            resolve_path(&mut pathspecs, &repos, arg)?;
+        }
+    }
+
+    Ok(pathspecs)
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_to_regex(glob).unwrap().is_match(path)
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn star_matches_across_directories() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", "src/command/main.rs"));
+        assert!(matches("src/**/main.rs", "src/command/main.rs"));
+        assert!(!matches("*.rs", "main.rsx"));
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(!matches("?.txt", ".txt"));
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn brackets_match_a_set() {
+        assert!(matches("[ab].txt", "b.txt"));
+        assert!(!matches("[ab].txt", "c.txt"));
+        assert!(matches("[!ab].txt", "c.txt"));
+        assert!(!matches("[!ab].txt", "a.txt"));
+        assert!(matches("[a-c].txt", "b.txt"));
+    }
+
+    //--------------------------------------------------------------------------
+    fn stripped(glob: &str, directory: &str) -> Vec<String> {
+        strip_glob_prefix(glob, directory).unwrap()
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn the_repo_is_taken_off_the_front_of_a_glob() {
+        assert_eq!(stripped("a/src/*.h", "a"), vec!["src/*.h"]);
+        assert_eq!(stripped("libs/a/*.h", "libs/a"), vec!["*.h"]);
+        assert_eq!(stripped("?/*.h", "a"), vec!["*.h"]);
+        assert_eq!(stripped("[ab]/*.h", "b"), vec!["*.h"]);
+        assert!(stripped("b/*.h", "a").is_empty());
+        assert!(stripped("[!ab]/*.h", "a").is_empty());
+        assert!(stripped("a/", "a").is_empty());
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn a_wildcard_can_match_the_repo_too() {
+        assert_eq!(stripped("*.h", "a"), vec!["*.h"]);
+        assert_eq!(stripped("*/src/*.rs", "a"), vec!["*/src/*.rs", "src/*.rs"]);
+        assert_eq!(
+            stripped("li*/src/*.rs", "libs/a"),
+            vec!["*/src/*.rs", "src/*.rs"]
+        );
+        assert_eq!(stripped("a*", "a"), vec!["*"]);
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn everything_else_is_literal() {
+        assert!(matches("a.b+c(d)", "a.b+c(d)"));
+        assert!(!matches("a.b", "axb"));
+        assert!(!matches("a", "ab"));
+    }
+}
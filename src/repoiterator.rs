@@ -2,7 +2,6 @@ use super::channel;
 use super::path;
 use super::result;
 //------------------------------------------------------------------------------
-use regex;
 use std::fs;
 use std::io::Write;
 use std::thread;
@@ -92,9 +91,10 @@ impl Iterator for RepoIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.recv.recv() {
             Ok(result) => result,
-            Err(error) => match writeln!(std::io::stderr(), "{0}", error) {
-                _ => None,
-            },
+            Err(error) => {
+                let _ = writeln!(std::io::stderr(), "{0}", error);
+                None
+            }
         }
     }
 }
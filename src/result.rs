@@ -16,8 +16,6 @@ pub fn get<S>(option: Option<S>) -> Result<S> {
 //------------------------------------------------------------------------------
 pub fn handle_errors<R>(result: Result<R>) {
     if let Err(error) = result {
-        match writeln!(std::io::stderr(), "{0}", error) {
-            _ => (), // Do nothing
-        }
+        let _ = writeln!(std::io::stderr(), "{0}", error);
     }
 }
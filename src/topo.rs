@@ -0,0 +1,213 @@
+use super::error::Error;
+use super::path;
+use super::result::Result;
+//------------------------------------------------------------------------------
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+
+//------------------------------------------------------------------------------
+// Each line of this file, in the root of a repo, is the path of another repo
+// it depends on, relative to the repo. Blank lines and lines starting with
+// '#' are ignored.
+pub const DEPENDS_FILE: &str = ".git-poly-depends";
+
+//------------------------------------------------------------------------------
+// Node
+//------------------------------------------------------------------------------
+pub struct Node {
+    pub path: path::PathBuf,
+    pub depends_on: Vec<path::PathBuf>,
+}
+
+//------------------------------------------------------------------------------
+fn read_dependencies(repo: &path::Path) -> Result<Vec<path::PathBuf>> {
+    let mut dependencies = Vec::new();
+
+    let file_path = repo.join(DEPENDS_FILE);
+    if file_path.exists() {
+        let file = BufReader::new(fs::File::open(file_path)?);
+        for line in file.lines() {
+            let line = line?;
+            let entry = line.trim();
+            if !entry.is_empty() && !entry.starts_with('#') {
+                dependencies.push(repo.join(entry));
+            }
+        }
+    }
+
+    Ok(dependencies)
+}
+
+//------------------------------------------------------------------------------
+// Walk from repo to repo through the dependencies that haven't been placed,
+// until we get back to one we've already seen.
+fn find_cycle(
+    depends_on: &[Vec<usize>],
+    placed: &[bool],
+    start: usize,
+) -> Vec<usize> {
+    let mut walked = vec![start];
+    loop {
+        let current = walked[walked.len() - 1];
+        let next = depends_on[current]
+            .iter()
+            .find(|dependency| !placed[**dependency]);
+
+        match next {
+            Some(next) => {
+                if let Some(position) = walked.iter().position(|i| i == next) {
+                    return walked.split_off(position);
+                }
+                walked.push(*next);
+            }
+            None => return walked,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Split the repos into waves, where every repo comes in a later wave than
+// the repos it depends on. Dependencies on repos that aren't in the list are
+// ignored.
+pub fn waves(repos: Vec<path::PathBuf>) -> Result<Vec<Vec<Node>>> {
+    // Repos are matched up by their canonical path
+    let mut index = HashMap::new();
+    for (i, repo) in repos.iter().enumerate() {
+        index.insert(fs::canonicalize(repo)?, i);
+    }
+
+    let mut depends_on = Vec::new();
+    for (i, repo) in repos.iter().enumerate() {
+        let mut dependencies = Vec::new();
+        for dependency in read_dependencies(repo)? {
+            match fs::canonicalize(&dependency) {
+                Ok(canonical) => {
+                    if let Some(j) = index.get(&canonical) {
+                        if *j != i {
+                            dependencies.push(*j);
+                        }
+                    }
+                }
+                Err(_) => {
+                    writeln!(
+                        std::io::stderr(),
+                        "{0}: unable to find dependency '{1}'",
+                        repo.display(),
+                        dependency.display()
+                    )?;
+                }
+            }
+        }
+        depends_on.push(dependencies);
+    }
+
+    // Peel off the repos whose dependencies have all been placed
+    let mut placed = vec![false; repos.len()];
+    let mut remaining = repos.len();
+    let mut waves = Vec::new();
+    while remaining != 0 {
+        let wave: Vec<usize> = (0..repos.len())
+            .filter(|i| !placed[*i])
+            .filter(|i| depends_on[*i].iter().all(|j| placed[*j]))
+            .collect();
+
+        if wave.is_empty() {
+            let start = placed.iter().position(|p| !p).unwrap_or(0);
+            let cycle = find_cycle(&depends_on, &placed, start);
+            return Err(Error::DependencyCycle(
+                cycle.iter().map(|i| repos[*i].clone()).collect(),
+            ));
+        }
+
+        for i in &wave {
+            placed[*i] = true;
+        }
+        remaining -= wave.len();
+
+        waves.push(
+            wave.iter()
+                .map(|i| Node {
+                    path: repos[*i].clone(),
+                    depends_on: depends_on[*i]
+                        .iter()
+                        .map(|j| repos[*j].clone())
+                        .collect(),
+                })
+                .collect(),
+        );
+    }
+
+    Ok(waves)
+}
+
+//------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //--------------------------------------------------------------------------
+    // Repos in a folder of their own, each depending on the ones listed
+    fn make_repos(test: &str, repos: &[(&str, &[&str])]) -> path::PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "git-p-topo-{0}-{1}",
+            std::process::id(),
+            test
+        ));
+        for (name, dependencies) in repos {
+            let repo = root.join(name);
+            fs::create_dir_all(&repo).unwrap();
+            let lines: Vec<String> = dependencies
+                .iter()
+                .map(|dependency| format!("../{0}\n", dependency))
+                .collect();
+            fs::write(repo.join(DEPENDS_FILE), lines.concat()).unwrap();
+        }
+        root
+    }
+
+    //--------------------------------------------------------------------------
+    fn names(nodes: &[Node]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| node.path.file_name().unwrap())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect()
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn dependencies_come_first() {
+        let root = make_repos(
+            "order",
+            &[("app", &["lib", "util"]), ("lib", &["util"]), ("util", &[])],
+        );
+        let repos = ["app", "lib", "util"].iter().map(|r| root.join(r));
+
+        let waves = waves(repos.collect()).unwrap();
+        let waves: Vec<Vec<String>> =
+            waves.iter().map(|wave| names(wave)).collect();
+        assert_eq!(waves, vec![vec!["util"], vec!["lib"], vec!["app"]]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    //--------------------------------------------------------------------------
+    #[test]
+    fn a_cycle_names_just_the_repos_in_it() {
+        let root = make_repos(
+            "cycle",
+            &[("app", &["lib"]), ("lib", &["util"]), ("util", &["lib"])],
+        );
+        let repos = ["app", "lib", "util"].iter().map(|r| root.join(r));
+
+        match waves(repos.collect()) {
+            Err(Error::DependencyCycle(cycle)) => {
+                assert_eq!(cycle, vec![root.join("lib"), root.join("util")]);
+            }
+            _ => panic!("the cycle wasn't found"),
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}